@@ -0,0 +1,6 @@
+// The firmware modules which don't touch the hardware, built for the host so
+// their unit tests can run, with `make test` in the application folder. The
+// firmware crate itself only builds for thumbv7em-none-eabihf.
+
+#[path = "../../src/sequence.rs"]
+pub mod sequence;
@@ -0,0 +1,303 @@
+use core::fmt::{self, Write};
+
+use rtic::Mutex;
+use stm32f4xx_hal::{pac, prelude::*, timer};
+
+use crate::edgelog::Line;
+use crate::sequence::{self, Aliases, Pin, ALIAS_LEN, PINS};
+
+// Logic analyzer style capture of the CTL lines: the TIM5 interrupt samples the
+// input data register of GPIOA, which reads the level of the CTL pins held by
+// CTLPins in any mode, at a fixed rate into a buffer of CAPTURE_LEN samples.
+//
+// A sample is a byte with bit n set when PINS[n] is high, and bit 5 for the
+// power enable line, the format of the sigrok "binary" input with 6 channels,
+// i.e. sigrok-cli -I binary:numchannels=6:samplerate=10000 -i capture.bin
+// The capture can also be printed as VCD, which sigrok and PulseView import.
+//
+// While waiting for the trigger the buffer is used as a ring, so up to
+// PRETRIGGER_LEN samples before the trigger are kept.
+
+pub const CAPTURE_LEN: usize = 8192; // a power of 2, so the ring index is cheap
+const PRETRIGGER_LEN: u32 = CAPTURE_LEN as u32 / 8;
+
+// the sampling runs in an interrupt, which limits the rate
+pub const MAX_RATE: u32 = 50_000;
+
+const POWER_SAMPLE_BIT: u8 = 1 << 5;
+
+// samples copied at once from the capture to be printed as VCD
+const VCD_CHUNK_LEN: usize = 256;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Trigger {
+    Now,
+    Edge(Line, Option<bool>), // rising, falling, or any edge when None
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CaptureState {
+    Idle,
+    Armed,     // sampling, waiting for the trigger
+    Triggered, // sampling until the buffer is full
+    Done,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CaptureError {
+    InvalidRate,
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CaptureError::InvalidRate => write!(f, "the rate must be between 1Hz and {}Hz", MAX_RATE),
+        }
+    }
+}
+
+pub struct Capture {
+    timer: timer::CounterHz<pac::TIM5>,
+    samples: &'static mut [u8; CAPTURE_LEN],
+    state: CaptureState,
+    rate: u32,
+    trigger: Trigger,
+    pos: u32,                // samples taken since the start, the next one goes to pos % CAPTURE_LEN
+    start: u32,              // first sample of the capture window
+    triggered: Option<u32>,  // sample which matched the trigger
+    previous: u8,
+}
+
+impl Capture {
+    pub fn new(timer: timer::CounterHz<pac::TIM5>, samples: &'static mut [u8; CAPTURE_LEN]) -> Self {
+        Capture { timer, samples, state: CaptureState::Idle, rate: 0, trigger: Trigger::Now,
+                  pos: 0, start: 0, triggered: None, previous: 0 }
+    }
+
+    /// Starts sampling at rate Hz, the previous capture is discarded.
+    pub fn start(&mut self, rate: u32, trigger: Trigger) -> Result<(), CaptureError> {
+        if rate == 0 || rate > MAX_RATE {
+            return Err(CaptureError::InvalidRate);
+        }
+        self.stop();
+        self.rate = rate;
+        self.trigger = trigger;
+        self.pos = 0;
+        self.start = 0;
+        self.triggered = None;
+        self.previous = read_sample();
+        self.state = CaptureState::Armed;
+        self.timer.start(rate.Hz()).map_err(|_| CaptureError::InvalidRate)?;
+        self.timer.listen(timer::Event::Update);
+        Ok(())
+    }
+
+    /// Stops sampling, the samples taken so far are kept.
+    pub fn stop(&mut self) {
+        self.timer.unlisten(timer::Event::Update);
+        self.timer.cancel().ok();
+        if self.state == CaptureState::Armed || self.state == CaptureState::Triggered {
+            if self.triggered.is_none() {
+                // no trigger, keep the last samples
+                self.start = self.pos.saturating_sub(CAPTURE_LEN as u32);
+            }
+            self.state = CaptureState::Done;
+        }
+    }
+
+    /// Takes a sample, called from the timer interrupt.
+    pub fn sample(&mut self) {
+        self.timer.clear_flags(timer::Flag::Update);
+        let sample = read_sample();
+        match self.state {
+            CaptureState::Armed | CaptureState::Triggered => {}
+            _ => return,
+        }
+        self.samples[self.pos as usize % CAPTURE_LEN] = sample;
+        if self.state == CaptureState::Armed && self.matches(self.previous, sample) {
+            self.triggered = Some(self.pos);
+            self.start = self.pos.saturating_sub(PRETRIGGER_LEN);
+            self.state = CaptureState::Triggered;
+        }
+        self.previous = sample;
+        self.pos += 1;
+        if self.state == CaptureState::Triggered && self.pos - self.start == CAPTURE_LEN as u32 {
+            self.stop();
+        }
+    }
+
+    fn matches(&self, previous: u8, sample: u8) -> bool {
+        match self.trigger {
+            Trigger::Now => true,
+            Trigger::Edge(line, edge) => {
+                let bit = sample_bit(line);
+                let (before, after) = (previous & bit != 0, sample & bit != 0);
+                before != after && edge.map_or(true, |rising| rising == after)
+            }
+        }
+    }
+
+    pub fn state(&self) -> CaptureState {
+        self.state
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Returns the number of samples in the capture window.
+    pub fn len(&self) -> usize {
+        match self.state {
+            CaptureState::Idle => 0,
+            CaptureState::Armed => self.pos.min(CAPTURE_LEN as u32) as usize,
+            _ => (self.pos - self.start) as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the index of the trigger sample in the capture window.
+    pub fn trigger_index(&self) -> Option<usize> {
+        self.triggered.map(|t| (t - self.start) as usize)
+    }
+
+    /// Returns the samples of the capture window, starting at index.
+    pub fn samples(&self, index: usize) -> impl Iterator<Item = u8> + '_ {
+        let first = self.pos as usize - self.len();
+        (first + index..self.pos as usize).map(move |i| self.samples[i % CAPTURE_LEN])
+    }
+}
+
+/// Writes the capture window as a VCD file, naming the pins after their
+/// aliases if they have one. The time is relative to the first sample.
+///
+/// The samples are written from index on, the header only when index is 0,
+/// and the value changes stop before max_len bytes are written. Returns the
+/// index of the next sample to write, None once the capture has been written.
+///
+/// The capture is only locked to copy chunks of samples, so the sampling
+/// interrupt is not delayed while they are formatted.
+pub fn write_vcd<M>(capture: &mut M, w: &mut dyn Write, aliases: &Aliases, index: usize, max_len: usize) -> Option<usize>
+where
+    M: Mutex<T = Capture>,
+{
+    let mut w = Counter { w, len: 0 };
+    let mut index = index;
+    let mut previous = None;
+    loop {
+        let mut samples: heapless::Vec<u8, VCD_CHUNK_LEN> = heapless::Vec::new();
+        let (rate, len, trigger) = capture.lock(|capture| {
+            if previous.is_none() && index > 0 {
+                previous = capture.samples(index - 1).next();
+            }
+            samples.extend(capture.samples(index).take(VCD_CHUNK_LEN));
+            (capture.rate.max(1) as u64, capture.len(), capture.trigger_index())
+        });
+        if index == 0 {
+            write_vcd_header(&mut w, aliases, trigger);
+        }
+
+        for (i, &sample) in samples.iter().enumerate() {
+            let changed = match previous {
+                Some(previous) => previous ^ sample,
+                None => 0xff,
+            };
+            if changed == 0 {
+                continue;
+            }
+            let mut change = heapless::String::<64>::new();
+            write!(change, "#{}\r\n", (index + i) as u64 * 1_000_000_000 / rate).ok();
+            for bit in 0..=PINS.len() {
+                if changed & (1 << bit) != 0 {
+                    write!(change, "{}{}\r\n", (sample >> bit) & 1, vcd_id(bit)).ok();
+                }
+            }
+            if w.len + change.len() > max_len {
+                return Some(index + i);
+            }
+            w.write_str(&change).ok();
+            previous = Some(sample);
+        }
+        index += samples.len();
+
+        if index >= len {
+            // the end of the capture, so the last levels have a duration
+            let mut end = heapless::String::<32>::new();
+            write!(end, "#{}\r\n", len as u64 * 1_000_000_000 / rate).ok();
+            if w.len + end.len() > max_len {
+                return Some(index);
+            }
+            w.write_str(&end).ok();
+            return None;
+        }
+    }
+}
+
+fn write_vcd_header(w: &mut dyn Write, aliases: &Aliases, trigger: Option<usize>) {
+    write!(w, "$timescale 1 ns $end\r\n$scope module dutlink $end\r\n").ok();
+    for (i, pin) in PINS.iter().enumerate() {
+        write!(w, "$var wire 1 {} ", vcd_id(i)).ok();
+        write_line_name(w, *pin, aliases);
+        write!(w, " $end\r\n").ok();
+    }
+    write!(w, "$var wire 1 {} POWER $end\r\n", vcd_id(PINS.len())).ok();
+    write!(w, "$upscope $end\r\n").ok();
+    if let Some(index) = trigger {
+        write!(w, "$comment trigger at sample {} $end\r\n", index).ok();
+    }
+    write!(w, "$enddefinitions $end\r\n").ok();
+}
+
+// counts the bytes written, so the VCD output fits in the USB serial buffer
+struct Counter<'a> {
+    w: &'a mut dyn Write,
+    len: usize,
+}
+
+impl Write for Counter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.len += s.len();
+        self.w.write_str(s)
+    }
+}
+
+// samples the CTL pins and the power enable line, bit n for PINS[n]
+fn read_sample() -> u8 {
+    let idr = unsafe { (*pac::GPIOA::ptr()).idr.read().bits() };
+    // PA9 is RESET, PA5 to PA8 are CTL_A to CTL_D and PA4 the power enable
+    (((idr >> 9) & 1) | (((idr >> 5) & 0xf) << 1) | (((idr >> 4) & 1) << 5)) as u8
+}
+
+pub fn sample_bit(line: Line) -> u8 {
+    match line {
+        Line::Pin(pin) => 1 << sequence::pin_index(pin),
+        Line::Power => POWER_SAMPLE_BIT,
+    }
+}
+
+// VCD identifiers are printable characters starting at !
+fn vcd_id(index: usize) -> char {
+    (b'!' + index as u8) as char
+}
+
+// VCD names can't contain spaces, the alias replaces the pin name
+fn write_line_name(w: &mut dyn Write, pin: Pin, aliases: &Aliases) {
+    let alias = &aliases[sequence::pin_index(pin)];
+    if alias[0] != 0 {
+        let len = alias.iter().position(|c| *c == 0).unwrap_or(ALIAS_LEN);
+        for c in &alias[..len] {
+            w.write_char(*c as char).ok();
+        }
+        return;
+    }
+    let name = match pin {
+        Pin::A     => "CTL_A",
+        Pin::B     => "CTL_B",
+        Pin::C     => "CTL_C",
+        Pin::D     => "CTL_D",
+        Pin::Reset => "RESET",
+    };
+    w.write_str(name).ok();
+}
@@ -1,55 +1,164 @@
-use core::{mem::size_of, cmp::min};
+use core::{fmt, mem::size_of, cmp::min};
 
 use stm32f4xx_hal::flash::{LockedFlash, FlashExt};
 
+use crate::ctlpins::PinState;
+use crate::sequence::{self, Aliases, Pin, ALIAS_LEN, NO_ALIASES, PINS};
+
 // Configuration is stored in the 3'rd sector of the flash memory, starting at 0x0800_C000.
-// The sector is 16k, so we can store 16 ConfigBlocks of 1k each. The last one with 
+// The sector is 16k, so we can store 16 ConfigBlocks of 1k each. The last one with
 // the magic word is the valid one.
 // Each sector has a limited amout of times it can be erased, so we use the next free block
 // and only erase the sector when all blocks are used.
+//
+// The power and user defined sequences don't fit in a 1k block, they are stored the
+// same way in the 2'nd sector, starting at 0x0800_8000, in 4 SequenceBlocks of 4k.
+//
+// Older firmware versions stored the 32 byte power sequences in the 1k block (see
+// LegacyConfigBlock), those are migrated to the current format on boot: the new
+// blocks are written in free slots, then the magic word of the legacy blocks is
+// cleared, so a power loss during the migration never loses the configuration.
 
 const FLASH_SECTOR : u8 = 3;
+const SEQUENCE_FLASH_SECTOR : u8 = 2;
 const FLASH_BASE : usize = 0x0800_0000;
 const FLASH_CONFIG_BASE : usize = 0x0800_C000; // see memory.x
+const FLASH_SEQUENCE_BASE : usize = 0x0800_8000; // see memory.x
+const CONFIG_BLOCKS : usize = 16;
+const SEQUENCE_BLOCKS : usize = 4;
+
+pub const MAX_SEQUENCES: usize = 8;
+pub const SEQUENCE_NAME_LEN: usize = 16;
+pub const SEQUENCE_LEN: usize = 240;
+pub const POWER_SEQUENCE_LEN: usize = 256;
+
+const PADDING_LEN: usize = 1024-64-256-64-512-PINS.len()*ALIAS_LEN-PINS.len()-2-4;
+const SEQUENCE_PADDING_LEN: usize = 4096-MAX_SEQUENCES*(SEQUENCE_NAME_LEN+SEQUENCE_LEN)-3*POWER_SEQUENCE_LEN-4;
+
+// a user defined sequence, stored by name
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct NamedSequence {
+    pub name: [u8; SEQUENCE_NAME_LEN],
+    pub sequence: [u8; SEQUENCE_LEN],
+}
+
+impl NamedSequence {
+    pub const fn empty() -> Self {
+        NamedSequence { name: [0; SEQUENCE_NAME_LEN], sequence: [0; SEQUENCE_LEN] }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.name[0] == 0
+    }
+
+    pub fn name(&self) -> &[u8] {
+        trim_zeros(&self.name)
+    }
+
+    pub fn sequence(&self) -> &[u8] {
+        trim_zeros(&self.sequence)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ConfigError {
+    InvalidName,
+    AliasInUse, // the alias is already used by another pin
+    TooLong(usize), // maximum length
+    Full,
+    NotFound,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::InvalidName => write!(f, "invalid name, use up to {} letters, digits, - or _", SEQUENCE_NAME_LEN),
+            ConfigError::AliasInUse  => write!(f, "alias already used by another pin"),
+            ConfigError::TooLong(l)  => write!(f, "value too long, max {} bytes", l),
+            ConfigError::Full        => write!(f, "no free slots, max {} sequences", MAX_SEQUENCES),
+            ConfigError::NotFound    => write!(f, "not found"),
+        }
+    }
+}
 
 #[repr(C, packed)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct ConfigBlock {
     pub name: [u8; 64],       // device name
     pub tags: [u8; 256],      // device tags
     pub usb_console: [u8; 64], // separate usb console i.e. used for the orin agx board to access the USB only UEFI console
+    pub json : [u8; 512], // json blob config
     // New variables can go here, but make sure to update the padding below
     // the previously stored versions will be 0's due to the padding
-    pub power_on: [u8; 32], // power_on method i.e. "bL,w1,bZ"
-    pub power_off: [u8; 32], // power_off method i.e. "bL,w11,bZ"
-    pub power_rescue: [u8; 32], // power_off method i.e. "aL,rL,w1,rZ,w1,aZ"
-    pub json : [u8; 512], // json blob config
-    padding: [u8; 1024-64-256-64-4-32-32-32-512], // padding to make up for 1024 byte blocks
+    pub aliases: Aliases, // pin aliases i.e. REC, in PINS order, see set-config alias_*
+    boot_states: [u8; 5], // pin states applied at boot as sequence letters in PINS order, 0 is floating
+    i2c_pins: [u8; 2],    // SCL and SDA pins of the I2C master as PINS index + 1, 0 when not set
+    padding: [u8; PADDING_LEN], // padding to make up for 1024 byte blocks
     magic: u32,           // magic word to know if this flash config block is valid
 
 }
 
+const _: () = assert!(size_of::<ConfigBlock>() == 1024);
+
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct SequenceBlock {
+    pub power_on: [u8; POWER_SEQUENCE_LEN], // power_on method i.e. "bL,w1,bZ"
+    pub power_off: [u8; POWER_SEQUENCE_LEN], // power_off method i.e. "bL,w11,bZ"
+    pub power_rescue: [u8; POWER_SEQUENCE_LEN], // power_rescue method i.e. "aL,rL,w1,rZ,w1,aZ"
+    pub sequences: [NamedSequence; MAX_SEQUENCES], // user defined sequences, see the run command
+    padding: [u8; SEQUENCE_PADDING_LEN], // padding to make up for 4096 byte blocks
+    magic: u32,           // magic word to know if this flash sequence block is valid
+}
+
+const _: () = assert!(size_of::<SequenceBlock>() == 4096);
+
+// Config block format used by older firmware versions
+#[repr(C, packed)]
+struct LegacyConfigBlock {
+    name: [u8; 64],
+    tags: [u8; 256],
+    usb_console: [u8; 64],
+    power_on: [u8; 32],
+    power_off: [u8; 32],
+    power_rescue: [u8; 32],
+    json : [u8; 512],
+    _padding: [u8; 1024-64-256-64-4-32-32-32-512],
+    magic: u32,
+}
+
+const _: () = assert!(size_of::<LegacyConfigBlock>() == 1024);
+
+// the blocks stored in a flash sector, see FlashArea
+trait FlashBlock {
+    fn is_valid(&self) -> bool;
+    fn is_erased(&self) -> bool;
+    fn format_error(&self) -> bool;
+}
+
 impl ConfigBlock {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         ConfigBlock {
             name: [0; 64],
             tags: [0; 256],
             usb_console: [0; 64],
-            power_on: [0; 32],
-            power_off: [0; 32],
-            power_rescue: [0; 32],
             json : [0; 512], // json blob config
+            aliases: NO_ALIASES,
+            boot_states: [0; 5],
+            i2c_pins: [0; 2],
             magic: MAGIC,
-            padding: [0; 1024-64-256-64-4-32-32-32-512],
+            padding: [0; PADDING_LEN],
         }
     }
 
-    pub fn is_valid(&self) -> bool {
-        self.magic == MAGIC
-    }
-
-    pub fn format_error(&self) -> bool {
-        self.magic != MAGIC && self.magic != 0xFFFF_FFFF
+    fn from_legacy(legacy: &LegacyConfigBlock) -> Self {
+        let mut cfg = ConfigBlock::new();
+        cfg.name = legacy.name;
+        cfg.tags = legacy.tags;
+        cfg.usb_console = legacy.usb_console;
+        cfg.json = legacy.json;
+        cfg
     }
 
     pub fn set_name(mut self,name: &[u8]) -> Self {
@@ -80,103 +189,317 @@ impl ConfigBlock {
         self
     }
 
-    pub fn set_power_on(mut self, power_on: &[u8]) -> Self {
-        let l = min(power_on.len(), self.power_on.len());
-        self.power_on[..l].copy_from_slice(&power_on[..l]);
-        self.power_on[l..].fill(0);
-        self
+    pub fn alias(&self, pin: Pin) -> &[u8] {
+        trim_zeros(&self.aliases[sequence::pin_index(pin)])
     }
-    pub fn set_power_off(mut self, power_off: &[u8]) -> Self {
-        let l = min(power_off.len(), self.power_off.len());
-        self.power_off[..l].copy_from_slice(&power_off[..l]);
-        self.power_off[l..].fill(0);
+
+    // aliases are names like the sequence ones, which can't be mistaken for a
+    // pin letter, an empty alias removes it
+    pub fn set_alias(mut self, pin: Pin, alias: &[u8]) -> Result<Self, ConfigError> {
+        if alias.len() > 0 {
+            if !valid_sequence_name(alias) || alias.len() > ALIAS_LEN ||
+               (alias.len() == 1 && b"rabcdRABCD".contains(&alias[0])) {
+                return Err(ConfigError::InvalidName);
+            }
+            let in_use = PINS.iter().any(|p| *p != pin && self.alias(*p).eq_ignore_ascii_case(alias));
+            if in_use {
+                return Err(ConfigError::AliasInUse);
+            }
+        }
+        let field = &mut self.aliases[sequence::pin_index(pin)];
+        field[..alias.len()].copy_from_slice(alias);
+        field[alias.len()..].fill(0);
+        Ok(self)
+    }
+
+    pub fn boot_state(&self, pin: Pin) -> PinState {
+        sequence::pin_state_from_letter(self.boot_states[sequence::pin_index(pin)]).unwrap_or(PinState::Floating)
+    }
+
+    pub fn boot_states(&self) -> [PinState; 5] {
+        PINS.map(|pin| self.boot_state(pin))
+    }
+
+    pub fn set_boot_state(mut self, pin: Pin, state: PinState) -> Self {
+        self.boot_states[sequence::pin_index(pin)] = sequence::pin_state_letter(state);
         self
     }
 
-    pub fn set_power_rescue(mut self, power_rescue: &[u8]) -> Self {
-        let l = min(power_rescue.len(), self.power_rescue.len());
-        self.power_rescue[..l].copy_from_slice(&power_rescue[..l]);
-        self.power_rescue[l..].fill(0);
+    /// Returns the SCL and SDA pins of the I2C master, if they are set.
+    pub fn i2c_pins(&self) -> Option<(Pin, Pin)> {
+        match self.i2c_pins {
+            [scl @ 1..=5, sda @ 1..=5] => Some((PINS[scl as usize - 1], PINS[sda as usize - 1])),
+            _ => None,
+        }
+    }
+
+    pub fn set_i2c_pins(mut self, pins: Option<(Pin, Pin)>) -> Self {
+        self.i2c_pins = match pins {
+            Some((scl, sda)) => [sequence::pin_index(scl) as u8 + 1, sequence::pin_index(sda) as u8 + 1],
+            None => [0; 2],
+        };
         self
     }
+}
+
+impl FlashBlock for ConfigBlock {
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC
+    }
+
+    fn is_erased(&self) -> bool {
+        self.magic == 0xFFFF_FFFF
+    }
 
+    fn format_error(&self) -> bool {
+        self.magic != MAGIC && self.magic != RETIRED_MAGIC && self.magic != 0xFFFF_FFFF
+    }
 }
 
-const MAGIC: u32 = 0x601dbeef;
+impl SequenceBlock {
+    pub const fn new() -> Self {
+        SequenceBlock {
+            power_on: [0; POWER_SEQUENCE_LEN],
+            power_off: [0; POWER_SEQUENCE_LEN],
+            power_rescue: [0; POWER_SEQUENCE_LEN],
+            sequences: [NamedSequence::empty(); MAX_SEQUENCES],
+            padding: [0; SEQUENCE_PADDING_LEN],
+            magic: SEQUENCE_MAGIC,
+        }
+    }
 
-// The flash area in 0x0800_C000 - 0x0800_FFFF is reserved for the config block.
-#[repr(C, packed)]
-struct ConfigAreaFlash {
-    config: [ConfigBlock; 16],
+    fn from_legacy(legacy: &LegacyConfigBlock) -> Self {
+        let mut seqs = SequenceBlock::new();
+        seqs.power_on[..32].copy_from_slice(&legacy.power_on);
+        seqs.power_off[..32].copy_from_slice(&legacy.power_off);
+        seqs.power_rescue[..32].copy_from_slice(&legacy.power_rescue);
+        seqs
+    }
+
+    pub fn set_power_on(mut self, power_on: &[u8]) -> Result<Self, ConfigError> {
+        set_sequence_field(&mut self.power_on, power_on)?;
+        Ok(self)
+    }
+
+    pub fn set_power_off(mut self, power_off: &[u8]) -> Result<Self, ConfigError> {
+        set_sequence_field(&mut self.power_off, power_off)?;
+        Ok(self)
+    }
+
+    pub fn set_power_rescue(mut self, power_rescue: &[u8]) -> Result<Self, ConfigError> {
+        set_sequence_field(&mut self.power_rescue, power_rescue)?;
+        Ok(self)
+    }
+
+    pub fn get_sequence(&self, name: &[u8]) -> Option<&NamedSequence> {
+        self.sequences.iter().find(|s| !s.is_empty() && s.name() == name)
+    }
+
+    pub fn set_sequence(mut self, name: &[u8], sequence: &[u8]) -> Result<Self, ConfigError> {
+        if !valid_sequence_name(name) {
+            return Err(ConfigError::InvalidName);
+        }
+        if sequence.len() > SEQUENCE_LEN {
+            return Err(ConfigError::TooLong(SEQUENCE_LEN));
+        }
+        // replace the sequence with the same name, or take the first free slot
+        let slot = match self.sequences.iter().position(|s| !s.is_empty() && s.name() == name) {
+            Some(i) => i,
+            None => self.sequences.iter().position(|s| s.is_empty()).ok_or(ConfigError::Full)?,
+        };
+        let mut entry = NamedSequence::empty();
+        entry.name[..name.len()].copy_from_slice(name);
+        entry.sequence[..sequence.len()].copy_from_slice(sequence);
+        self.sequences[slot] = entry;
+        Ok(self)
+    }
+
+    pub fn delete_sequence(mut self, name: &[u8]) -> Result<Self, ConfigError> {
+        let slot = self.sequences.iter().position(|s| !s.is_empty() && s.name() == name)
+                                        .ok_or(ConfigError::NotFound)?;
+        self.sequences[slot] = NamedSequence::empty();
+        Ok(self)
+    }
+
+}
+
+impl FlashBlock for SequenceBlock {
+    fn is_valid(&self) -> bool {
+        self.magic == SEQUENCE_MAGIC
+    }
+
+    fn is_erased(&self) -> bool {
+        self.magic == 0xFFFF_FFFF
+    }
+
+    fn format_error(&self) -> bool {
+        self.magic != SEQUENCE_MAGIC && self.magic != 0xFFFF_FFFF
+    }
+}
+
+// sequences are never truncated, as a truncated sequence would still parse
+// and do something different
+fn set_sequence_field(field: &mut [u8], sequence: &[u8]) -> Result<(), ConfigError> {
+    if sequence.len() > field.len() {
+        return Err(ConfigError::TooLong(field.len()));
+    }
+    field[..sequence.len()].copy_from_slice(sequence);
+    field[sequence.len()..].fill(0);
+    Ok(())
+}
+
+fn valid_sequence_name(name: &[u8]) -> bool {
+    name.len() > 0 && name.len() <= SEQUENCE_NAME_LEN &&
+        name.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'-' || *c == b'_')
+}
+
+// a zero padded field without the padding
+fn trim_zeros(val: &[u8]) -> &[u8] {
+    match val.iter().position(|c| *c == 0) {
+        Some(l) => &val[..l],
+        None => val,
+    }
+}
+
+const MAGIC: u32 = 0x601d5e03;
+const SEQUENCE_MAGIC: u32 = 0x5e9d5e01;
+const LEGACY_MAGIC: u32 = 0x601dbeef;
+// the magic word of a migrated legacy block, programming flash only clears bits
+const RETIRED_MAGIC: u32 = 0;
+
+// used until a block is written to an empty sector
+static EMPTY_CONFIG: ConfigBlock = ConfigBlock::new();
+pub static EMPTY_SEQUENCES: SequenceBlock = SequenceBlock::new();
+
+// The flash sectors in 0x0800_8000 - 0x0800_FFFF are reserved for the config blocks.
+#[repr(C)]
+struct FlashArea<T, const N: usize> {
+    blocks: [T; N],
     // DO NOT ADD MORE VARIABLES HERE
 }
 
+// The config sector as written by older firmware versions
+#[repr(C, packed)]
+struct LegacyConfigAreaFlash {
+    config: [LegacyConfigBlock; CONFIG_BLOCKS],
+}
+
 pub struct ConfigArea {
-    flash_config: &'static ConfigAreaFlash,
+    flash_config: &'static FlashArea<ConfigBlock, CONFIG_BLOCKS>,
+    flash_sequences: &'static FlashArea<SequenceBlock, SEQUENCE_BLOCKS>,
     flash: LockedFlash,
 }
 
 impl ConfigArea {
     pub fn new(flash: LockedFlash) -> Self {
         let mut cfg = ConfigArea {
-            flash_config: ConfigAreaFlash::new(),
+            flash_config: FlashArea::at(FLASH_CONFIG_BASE),
+            flash_sequences: FlashArea::at(FLASH_SEQUENCE_BASE),
             flash: flash,
         };
+        if cfg.flash_sequences.format_error() {
+            cfg.erase_flash(SEQUENCE_FLASH_SECTOR);
+        }
+        if cfg.flash_config.get_current().is_none() {
+            let legacy = LegacyConfigAreaFlash::new();
+            if let Some(legacy_cfg) = legacy.get_config() {
+                // move the configuration from an older firmware to the current format,
+                // the config block goes in a free slot next to the legacy blocks
+                let migrated = ConfigBlock::from_legacy(legacy_cfg);
+                let sequences = SequenceBlock::from_legacy(legacy_cfg);
+                cfg.write_sequences(&sequences).ok();
+                cfg.write_config(&migrated).ok();
+            }
+        }
+        // also finishes a migration interrupted before the legacy blocks were retired
+        cfg.retire_legacy_blocks();
         if cfg.flash_config.format_error() {
-            cfg.erase_flash();
+            cfg.erase_flash(FLASH_SECTOR);
         }
         cfg
     }
 
-    pub fn get(&self) -> ConfigBlock {
-        self.flash_config.ram_config()
+    pub fn get(&self) -> &ConfigBlock {
+        self.flash_config.get().unwrap_or(&EMPTY_CONFIG)
     }
 
-    fn erase_flash(&mut self) {
+    // read in place from the flash, so it can be kept instead of copying 4k
+    pub fn sequences(&self) -> &'static SequenceBlock {
+        self.flash_sequences.get().unwrap_or(&EMPTY_SEQUENCES)
+    }
+
+    // clears the magic word of the legacy blocks once they have been migrated
+    fn retire_legacy_blocks(&mut self) {
+        if self.flash_config.get_current().is_none() {
+            return;
+        }
+        let legacy = LegacyConfigAreaFlash::new();
         let mut unlocked_flash = self.flash.unlocked();
-        unlocked_flash.erase(FLASH_SECTOR).unwrap();
+        for (i, block) in legacy.config.iter().enumerate() {
+            if block.magic == LEGACY_MAGIC {
+                let offset = FLASH_CONFIG_BASE - FLASH_BASE + (i + 1) * size_of::<LegacyConfigBlock>() - size_of::<u32>();
+                unlocked_flash.program(offset, RETIRED_MAGIC.to_le_bytes().iter()).unwrap();
+            }
+        }
+    }
+
+    fn erase_flash(&mut self, sector: u8) {
+        let mut unlocked_flash = self.flash.unlocked();
+        unlocked_flash.erase(sector).unwrap();
     }
+
     pub fn write_config(&mut self, cfg: &ConfigBlock) -> Result<(),()> {
         let next = self.flash_config.get_next();
+        self.write_block(FLASH_SECTOR, FLASH_CONFIG_BASE, next, cfg)
+    }
+
+    pub fn write_sequences(&mut self, seqs: &SequenceBlock) -> Result<(),()> {
+        let next = self.flash_sequences.get_next();
+        self.write_block(SEQUENCE_FLASH_SECTOR, FLASH_SEQUENCE_BASE, next, seqs)
+    }
+
+    // writes a block in the next free slot of a sector, or erases the sector
+    // and writes it in the first one when all slots are used
+    fn write_block<T>(&mut self, sector: u8, base: usize, next: Option<usize>, block: &T) -> Result<(),()> {
         let next_i: usize;
         match next {
             Some(i) => {
                 next_i = i;
             },
             None => {
-                self.erase_flash();
+                self.erase_flash(sector);
                 next_i = 0;
             },
         }
-        let offset = next_i * size_of::<ConfigBlock>();
+        let offset = next_i * size_of::<T>();
         let mut unlocked_flash = self.flash.unlocked();
-        let buffer = unsafe { as_u8_slice(cfg) };
-        let base = FLASH_CONFIG_BASE - FLASH_BASE;
-        unlocked_flash.program(base + offset, buffer.iter()).unwrap();
+        let buffer = unsafe { as_u8_slice(block) };
+        unlocked_flash.program(base - FLASH_BASE + offset, buffer.iter()).unwrap();
 
         Ok(())
     }
 }
 
-impl ConfigAreaFlash {
-    fn new() -> &'static Self {
-        let cfg = FLASH_CONFIG_BASE as *const ConfigAreaFlash;
-        return unsafe { &*cfg };
+impl<T: FlashBlock, const N: usize> FlashArea<T, N> {
+    fn at(base: usize) -> &'static Self {
+        let area = base as *const FlashArea<T, N>;
+        return unsafe { &*area };
     }
 
     fn get_next(&self) -> Option<usize> {
-        for i in 0..16 {
-            if !self.config[i].is_valid() {
+        for i in 0..N {
+            if self.blocks[i].is_erased() {
                 return Some(i)
             }
         }
         return None
     }
 
-    // detect if any of the config blocks have a format error (magic word is not 0x600dbeef of 0xffffffff)
-    pub fn format_error(&self) -> bool {
-        for i in 0..16 {
-            if self.config[i].format_error() {
+    // detect if any of the blocks have a format error (magic word is not the
+    // block magic, the one of a retired legacy block or 0xffffffff)
+    fn format_error(&self) -> bool {
+        for i in 0..N {
+            if self.blocks[i].format_error() {
                 return true
             }
         }
@@ -184,37 +507,34 @@ impl ConfigAreaFlash {
     }
 
     fn get_current(&self) -> Option<usize> {
-        for i in (0..16).rev() {
-            if self.config[i].is_valid() {
+        for i in (0..N).rev() {
+            if self.blocks[i].is_valid() {
                 return Some(i)
             }
         }
         return None
     }
 
-    fn get_config(&self) -> Option<&ConfigBlock> {
+    // the last valid block, read in place from the flash
+    fn get(&self) -> Option<&T> {
         match self.get_current() {
-            Some(i) => Some(&self.config[i]),
+            Some(i) => Some(&self.blocks[i]),
             None => None,
         }
     }
+}
 
-    // get the last config block, as a copy in RAM
-    fn ram_config(&self) -> ConfigBlock {
-        let mut ram_cfg = ConfigBlock::new();
 
-        match self.get_config() {
-            Some(cfg) => {
-                let src: &[u8] = unsafe { as_u8_slice(cfg) };
-                let dst: &mut [u8] = unsafe { as_mut_u8_slice(&mut ram_cfg) };
-                dst.copy_from_slice(src);
-            },
-            None => {},
-        };
-        ram_cfg
+impl LegacyConfigAreaFlash {
+    fn new() -> &'static Self {
+        let cfg = FLASH_CONFIG_BASE as *const LegacyConfigAreaFlash;
+        return unsafe { &*cfg };
     }
-}
 
+    fn get_config(&self) -> Option<&LegacyConfigBlock> {
+        self.config.iter().rev().find(|cfg| cfg.magic == LEGACY_MAGIC)
+    }
+}
 
 unsafe fn as_u8_slice<T: Sized>(p: &T) -> &[u8] {
     ::core::slice::from_raw_parts(
@@ -222,10 +542,3 @@ unsafe fn as_u8_slice<T: Sized>(p: &T) -> &[u8] {
         ::core::mem::size_of::<T>(),
     )
 }
-
-unsafe fn as_mut_u8_slice<T: Sized>(p: &T) -> &mut [u8] {
-    ::core::slice::from_raw_parts_mut(
-        (p as *const T) as *mut u8,
-        ::core::mem::size_of::<T>(),
-    )
-}
@@ -1,14 +1,19 @@
 use core::convert::TryInto;
-use core::fmt::Write;
+use core::fmt::{self, Write};
 
 use num_enum::TryFromPrimitive;
+use rtic::Mutex;
 use usb_device::class_prelude::*;
 use usb_device::control::{Recipient, Request, RequestType};
 use usb_device::Result;
 
-use crate::config::{ConfigArea, ConfigBlock};
-use crate::ctlpins::{CTLPinsTrait, PinState};
-use crate::powermeter::PowerMeter;
+use crate::config::{ConfigArea, ConfigBlock, SequenceBlock, EMPTY_SEQUENCES, SEQUENCE_LEN, SEQUENCE_NAME_LEN};
+use crate::edgelog::{Edge, EdgeLog, Line, EDGE_LOG_LEN};
+use crate::capture::{Capture, CaptureState, Trigger};
+use crate::ctlpins::{CTLPinsTrait, PinState, SequenceState, SetOutcome};
+use crate::i2c::{I2cError, MAX_ADDRESS, MAX_I2C_LEN};
+use crate::powermeter::{PowerMeter, JOULES_PER_WH};
+use crate::sequence::{self, ParseError, Pin, PinStates, SequenceError, PINS};
 use crate::storage::StorageSwitchTrait;
 
 const USB_CLASS_VENDOR_SPECIFIC: u8 = 0xff;
@@ -16,6 +21,8 @@ const USB_SUBCLASS_JUMPSTARTER: u8 = 0x01;
 const USB_PROTOCOL_JUMPSTARTER: u8 = 0x01;
 const MAX_CONFIG_LENGTH: usize = 256;
 const MAX_READ_LENGTH: usize = 128;
+// a named sequence as a name, a space and the sequence
+const MAX_SEQUENCE_ENTRY_LENGTH: usize = SEQUENCE_NAME_LEN + 1 + SEQUENCE_LEN;
 
 #[repr(u8)]
 #[derive(TryFromPrimitive)]
@@ -26,6 +33,15 @@ pub enum ControlRequest {
     Config,
     Read,
     Set,
+    Sequence,
+    Get,
+    Pulse,
+    SetPins,
+    EdgeLog,
+    Capture,
+    Pwm,
+    I2c,
+    Meter,
 }
 
 #[repr(u16)]
@@ -36,6 +52,7 @@ pub enum PowerAction {
     ForceOff,
     ForceOn,
     Rescue,
+    Abort,
 }
 
 #[repr(u16)]
@@ -55,6 +72,64 @@ pub enum ConfigKey {
     PowerOn,
     PowerOff,
     PowerRescue,
+    AliasReset,
+    AliasA,
+    AliasB,
+    AliasC,
+    AliasD,
+    BootReset, // data: the state applied at boot as a SetPinState
+    BootA,
+    BootB,
+    BootC,
+    BootD,
+    I2c, // data: the SCL and SDA pins of the I2C master as SetPin, none to clear them
+}
+
+#[repr(u16)]
+#[derive(TryFromPrimitive)]
+pub enum SequenceAction {
+    Run,    // data: name
+    Store,  // data: name, a space and the sequence
+    Delete, // data: name
+}
+
+#[repr(u16)]
+#[derive(TryFromPrimitive)]
+pub enum EdgeLogAction {
+    Stop,
+    Start, // data: a mask of the pins to capture, bit n for SetPin n
+    Clear,
+}
+
+#[repr(u16)]
+#[derive(TryFromPrimitive)]
+pub enum CaptureAction {
+    Stop,
+    Start, // data: the rate in Hz as a little endian u32, the trigger line (SetPin, 5 for the
+           // power enable, 0xff to start right away) and the edge (0 falling, 1 rising, 2 any)
+    Fetch, // data: the index of the first sample to read with CaptureKey::Samples, as a little endian u16
+}
+
+#[repr(u16)]
+#[derive(TryFromPrimitive)]
+pub enum CaptureKey {
+    Status,  // the CaptureState, the rate as a u32, the number of samples and the index of
+             // the trigger sample (0xffff if none) as u16, little endian, as of the last refresh
+    Samples, // the samples from the last fetch, a byte per sample with bit n for SetPin n
+             // and bit 5 for the power enable, as the sigrok binary input with 6 channels
+}
+
+#[repr(u16)]
+#[derive(TryFromPrimitive)]
+pub enum I2cAction {
+    Transfer, // data: the address, the number of bytes to read and the bytes to write before reading
+    Scan,
+}
+
+#[repr(u16)]
+#[derive(TryFromPrimitive)]
+pub enum MeterAction {
+    ResetEnergy,
 }
 
 #[repr(u16)]
@@ -64,6 +139,9 @@ pub enum ReadKey {
     Power,
     Voltage,
     Current,
+    Sequence,
+    Energy,
+    Error,
 }
 
 #[repr(u16)]
@@ -82,6 +160,47 @@ pub enum SetPinState {
     Low,
     High,
     Floating,
+    OpenDrainLow,
+    PullUp,
+    PullDown,
+}
+
+impl From<SetPin> for Pin {
+    fn from(pin: SetPin) -> Self {
+        match pin {
+            SetPin::Reset => Pin::Reset,
+            SetPin::A => Pin::A,
+            SetPin::B => Pin::B,
+            SetPin::C => Pin::C,
+            SetPin::D => Pin::D,
+        }
+    }
+}
+
+impl From<SetPinState> for PinState {
+    fn from(state: SetPinState) -> Self {
+        match state {
+            SetPinState::Low => PinState::Low,
+            SetPinState::High => PinState::High,
+            SetPinState::Floating => PinState::Floating,
+            SetPinState::OpenDrainLow => PinState::OpenDrainLow,
+            SetPinState::PullUp => PinState::PullUp,
+            SetPinState::PullDown => PinState::PullDown,
+        }
+    }
+}
+
+impl From<PinState> for SetPinState {
+    fn from(state: PinState) -> Self {
+        match state {
+            PinState::Low => SetPinState::Low,
+            PinState::High => SetPinState::High,
+            PinState::Floating => SetPinState::Floating,
+            PinState::OpenDrainLow => SetPinState::OpenDrainLow,
+            PinState::PullUp => SetPinState::PullUp,
+            PinState::PullDown => SetPinState::PullDown,
+        }
+    }
 }
 
 pub struct ControlClass {
@@ -89,7 +208,15 @@ pub struct ControlClass {
     config: Option<(ConfigKey, heapless::Vec<u8, MAX_CONFIG_LENGTH>)>,
     power: Option<PowerAction>,
     storage: Option<StorageAction>,
+    meter: Option<MeterAction>,
     pin: Option<(SetPin, SetPinState)>,
+    pulse: Option<(SetPin, SetPinState, u32)>,
+    pwm: Option<(SetPin, u32, u8)>,
+    pins: Option<PinStates>,
+    edge_log: Option<(EdgeLogAction, u8)>,
+    capture: Option<(CaptureAction, heapless::Vec<u8, MAX_CONFIG_LENGTH>)>,
+    i2c: Option<(I2cAction, heapless::Vec<u8, MAX_CONFIG_LENGTH>)>,
+    sequence: Option<(SequenceAction, heapless::Vec<u8, MAX_SEQUENCE_ENTRY_LENGTH>)>,
     refresh: Option<()>,
     data: Data,
 }
@@ -98,7 +225,17 @@ pub struct Data {
     power: f32,
     voltage: f32,
     current: f32,
+    energy: (f64, u64), // joules and microseconds since the last reset of the energy counter
+    sequence: SequenceState,
+    pins: [(PinState, bool, SetOutcome); 5], // state set, input level and whether the state is applied, in SetPin order
+    edges: heapless::Vec<Edge, EDGE_LOG_LEN>,
+    capture: (CaptureState, u32, u16, Option<u16>), // state, rate, number of samples and trigger index
+    samples: heapless::Vec<u8, MAX_READ_LENGTH>,
+    pwm: Option<(Pin, u32, u8, SetOutcome)>, // the PWM stored on a pin, frequency and duty cycle
+    i2c: Option<(Result<(), I2cError>, heapless::Vec<u8, MAX_READ_LENGTH>)>, // result of the last I2C action
+    error: heapless::Vec<u8, MAX_READ_LENGTH>, // error of the last action, empty if it succeeded
     config: ConfigBlock,
+    sequences: &'static SequenceBlock, // in flash, see ConfigArea::sequences
 }
 
 impl ControlClass {
@@ -107,14 +244,32 @@ impl ControlClass {
             iface: alloc.interface(),
             power: None,
             storage: None,
+            meter: None,
             pin: None,
+            pulse: None,
+            pwm: None,
+            pins: None,
+            edge_log: None,
+            capture: None,
+            i2c: None,
+            sequence: None,
             config: None,
             refresh: None,
             data: Data {
                 power: 0.0,
                 voltage: 0.0,
                 current: 0.0,
+                energy: (0.0, 0),
+                sequence: SequenceState::Idle,
+                pins: [(PinState::Floating, false, SetOutcome::Applied); 5],
+                edges: heapless::Vec::new(),
+                capture: (CaptureState::Idle, 0, 0, None),
+                samples: heapless::Vec::new(),
+                pwm: None,
+                i2c: None,
+                error: heapless::Vec::new(),
                 config: ConfigBlock::new(),
+                sequences: &EMPTY_SEQUENCES,
             },
         }
     }
@@ -127,13 +282,24 @@ impl ControlClass {
     /// the class to batch actions that need to be taken after all USB transactions have been completed,
     /// ensuring that changes are applied in a controlled manner.
     ///
-    pub fn post_poll<C: CTLPinsTrait, S: StorageSwitchTrait>(
+    /// The edge log and the capture are shared with the interrupts which fill
+    /// them, they are only locked around the operations on them, so the
+    /// interrupts are not delayed.
+    ///
+    pub fn post_poll<C, S, E, K>(
         &mut self,
         config: &mut ConfigArea,
         ctlpins: &mut C,
         storage: &mut S,
         power_meter: &mut dyn PowerMeter,
-    ) {
+        edge_log: &mut E,
+        capture: &mut K,
+    ) where
+        C: CTLPinsTrait,
+        S: StorageSwitchTrait,
+        E: Mutex<T = EdgeLog>,
+        K: Mutex<T = Capture>,
+    {
         if let Some((key, value)) = self.config.take() {
             match key {
                 ConfigKey::Name => {
@@ -148,36 +314,96 @@ impl ControlClass {
                     let cfg = config.get().set_usb_console(&value);
                     config.write_config(&cfg).ok();
                 }
-                ConfigKey::PowerOn => {
-                    let cfg = config.get().set_power_on(&value);
-                    config.write_config(&cfg).ok();
+                ConfigKey::PowerOn | ConfigKey::PowerOff | ConfigKey::PowerRescue => {
+                    // invalid sequences are never persisted to flash
+                    if let Err(e) = sequence::parse(&value, &config.get().aliases) {
+                        self.report(Err(SequenceError::Invalid(e)));
+                    } else {
+                        let seqs = match key {
+                            ConfigKey::PowerOn => config.sequences().set_power_on(&value),
+                            ConfigKey::PowerOff => config.sequences().set_power_off(&value),
+                            _ => config.sequences().set_power_rescue(&value),
+                        };
+                        match seqs {
+                            Ok(seqs) => { config.write_sequences(&seqs).ok(); }
+                            Err(e) => self.report(Err(e)),
+                        }
+                    }
                 }
-                ConfigKey::PowerOff => {
-                    let cfg = config.get().set_power_off(&value);
-                    config.write_config(&cfg).ok();
+                ConfigKey::AliasReset | ConfigKey::AliasA | ConfigKey::AliasB | ConfigKey::AliasC | ConfigKey::AliasD => {
+                    let pin = match key {
+                        ConfigKey::AliasReset => Pin::Reset,
+                        ConfigKey::AliasA => Pin::A,
+                        ConfigKey::AliasB => Pin::B,
+                        ConfigKey::AliasC => Pin::C,
+                        _ => Pin::D,
+                    };
+                    match config.get().set_alias(pin, &value) {
+                        Ok(cfg) => {
+                            config.write_config(&cfg).ok();
+                            ctlpins.set_aliases(cfg.aliases);
+                        }
+                        Err(e) => self.report(Err(e)),
+                    }
                 }
-                ConfigKey::PowerRescue => {
-                    let cfg = config.get().set_power_rescue(&value);
-                    config.write_config(&cfg).ok();
+                ConfigKey::BootReset | ConfigKey::BootA | ConfigKey::BootB | ConfigKey::BootC | ConfigKey::BootD => {
+                    let pin = match key {
+                        ConfigKey::BootReset => Pin::Reset,
+                        ConfigKey::BootA => Pin::A,
+                        ConfigKey::BootB => Pin::B,
+                        ConfigKey::BootC => Pin::C,
+                        _ => Pin::D,
+                    };
+                    let state = value.first().cloned().map(TryInto::<SetPinState>::try_into);
+                    if let Some(Ok(state)) = state {
+                        let cfg = config.get().set_boot_state(pin, PinState::from(state));
+                        config.write_config(&cfg).ok();
+                    } else {
+                        self.report(Err("invalid pin state"));
+                    }
+                }
+                ConfigKey::I2c => {
+                    let pin = |b: &u8| TryInto::<SetPin>::try_into(*b as u16).ok().map(Pin::from);
+                    let pins = match value.as_slice() {
+                        [] => Some(None),
+                        [scl, sda] => match (pin(scl), pin(sda)) {
+                            (Some(scl), Some(sda)) if scl != sda => Some(Some((scl, sda))),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    if let Some(pins) = pins {
+                        let cfg = config.get().set_i2c_pins(pins);
+                        config.write_config(&cfg).ok();
+                    } else {
+                        self.report(Err("invalid I2C pins"));
+                    }
                 }
             }
         }
         if let Some(action) = self.power.take() {
             match action {
                 PowerAction::Off => {
-                    ctlpins.power_off(&config.get().power_off);
+                    let result = ctlpins.power_stored(false, &config.sequences().power_off);
+                    self.report_stored(result);
                 }
                 PowerAction::On => {
-                    ctlpins.power_on(&config.get().power_on);
+                    let result = ctlpins.power_stored(true, &config.sequences().power_on);
+                    self.report_stored(result);
                 }
                 PowerAction::ForceOff => {
-                    ctlpins.power_off(&[]);
+                    ctlpins.power_off(&[]).ok();
                 }
                 PowerAction::ForceOn => {
-                    ctlpins.power_on(&[]);
+                    ctlpins.power_on(&[]).ok();
                 }
                 PowerAction::Rescue => {
-                    ctlpins.power_on(&config.get().power_rescue);
+                    let result = ctlpins.power_stored(true, &config.sequences().power_rescue);
+                    self.report_stored(result);
+                }
+                PowerAction::Abort => {
+                    let result = if ctlpins.abort_sequence() { Ok(()) } else { Err("no sequence in progress") };
+                    self.report(result);
                 }
             }
         }
@@ -194,12 +420,15 @@ impl ControlClass {
                 }
             }
         }
+        if let Some(action) = self.meter.take() {
+            match action {
+                MeterAction::ResetEnergy => {
+                    power_meter.reset_energy();
+                }
+            }
+        }
         if let Some((pin, state)) = self.pin.take() {
-            let state = match state {
-                SetPinState::Low => PinState::Low,
-                SetPinState::High => PinState::High,
-                SetPinState::Floating => PinState::Floating,
-            };
+            let state = PinState::from(state);
             match pin {
                 SetPin::Reset => {
                     ctlpins.set_reset(state);
@@ -218,11 +447,143 @@ impl ControlClass {
                 }
             }
         }
+        if let Some((pin, state, us)) = self.pulse.take() {
+            match ctlpins.pulse(Pin::from(pin), PinState::from(state), us) {
+                // the pin floats instead, as driving it would back-power the DUT
+                Ok(SetOutcome::Deferred) => self.report(Err("the DUT is off, pulsing the pin floating")),
+                result => self.report(result.map(|_| ())),
+            }
+        }
+        if let Some((pin, hz, duty)) = self.pwm.take() {
+            let result = ctlpins.set_pwm(Pin::from(pin), hz, duty);
+            self.report(result.map(|_| ()));
+        }
+        if let Some(states) = self.pins.take() {
+            ctlpins.set_pins(&states);
+        }
+        if let Some((action, mask)) = self.edge_log.take() {
+            edge_log.lock(|edge_log| match action {
+                EdgeLogAction::Stop => edge_log.stop(),
+                EdgeLogAction::Start => edge_log.start(mask),
+                EdgeLogAction::Clear => edge_log.clear(),
+            });
+        }
+        if let Some((action, data)) = self.capture.take() {
+            match action {
+                CaptureAction::Stop => capture.lock(|capture| capture.stop()),
+                CaptureAction::Start => {
+                    if let (Some(rate), Some(line), Some(edge)) = (data.get(0..4), data.get(4), data.get(5)) {
+                        let rate = u32::from_le_bytes(rate.try_into().unwrap());
+                        let line = match *line {
+                            0xff => None,
+                            5 => Some(Line::Power),
+                            line => TryInto::<SetPin>::try_into(line as u16).ok().map(|pin| Line::Pin(Pin::from(pin))),
+                        };
+                        let edge = match *edge {
+                            0 => Some(false),
+                            1 => Some(true),
+                            _ => None,
+                        };
+                        let trigger = line.map_or(Trigger::Now, |line| Trigger::Edge(line, edge));
+                        let result = capture.lock(|capture| capture.start(rate, trigger));
+                        self.report(result);
+                    }
+                }
+                CaptureAction::Fetch => {
+                    if let Some(index) = data.get(0..2) {
+                        let index = u16::from_le_bytes(index.try_into().unwrap()) as usize;
+                        self.data.samples = capture.lock(|capture| capture.samples(index).take(MAX_READ_LENGTH).collect());
+                    }
+                }
+            }
+        }
+        if let Some((action, data)) = self.i2c.take() {
+            let mut buf = heapless::Vec::<u8, MAX_READ_LENGTH>::new();
+            let result = match (config.get().i2c_pins(), action) {
+                (None, _) => Err(I2cError::NoPins),
+                (Some((scl, sda)), I2cAction::Scan) => {
+                    // the addresses which acknowledged
+                    ctlpins.i2c_scan(scl, sda, &mut |addr| { buf.push(addr).ok(); })
+                }
+                (Some((scl, sda)), I2cAction::Transfer) => {
+                    // the length of data was checked by control_out
+                    buf.resize_default(data[1] as usize).ok();
+                    ctlpins.i2c_transfer(scl, sda, data[0], &data[2..], &mut buf)
+                }
+            };
+            if result.is_err() {
+                buf.clear();
+            }
+            self.data.i2c = Some((result, buf));
+        }
+        if let Some((action, value)) = self.sequence.take() {
+            let seqs = config.sequences();
+            match action {
+                SequenceAction::Run => {
+                    match seqs.get_sequence(&value) {
+                        Some(entry) => {
+                            let result = ctlpins.run_sequence(entry.sequence());
+                            self.report(result);
+                        }
+                        None => self.report(Err("unknown sequence")),
+                    }
+                }
+                SequenceAction::Store => {
+                    let split = value.iter().position(|c| *c == b' ').unwrap_or(value.len());
+                    let (name, seq) = (&value[..split], &value[(split + 1).min(value.len())..]);
+                    if let Err(e) = sequence::parse(seq, &config.get().aliases) {
+                        self.report(Err(SequenceError::Invalid(e)));
+                    } else {
+                        match seqs.set_sequence(name, seq) {
+                            Ok(seqs) => { config.write_sequences(&seqs).ok(); }
+                            Err(e) => self.report(Err(e)),
+                        }
+                    }
+                }
+                SequenceAction::Delete => {
+                    match seqs.delete_sequence(&value) {
+                        Ok(seqs) => { config.write_sequences(&seqs).ok(); }
+                        Err(e) => self.report(Err(e)),
+                    }
+                }
+            }
+        }
         if let Some(()) = self.refresh.take() {
             self.data.power = power_meter.get_power();
             self.data.voltage = power_meter.get_voltage();
             self.data.current = power_meter.get_current();
-            self.data.config = config.get();
+            self.data.energy = (power_meter.get_energy(), power_meter.get_energy_time());
+            self.data.sequence = ctlpins.sequence_state();
+            for (data, pin) in self.data.pins.iter_mut().zip(PINS) {
+                *data = (ctlpins.stored_state(pin), ctlpins.input_level(pin), ctlpins.stored_outcome(pin));
+            }
+            self.data.config = *config.get();
+            self.data.sequences = config.sequences();
+            self.data.edges = edge_log.lock(|edge_log| edge_log.edges().cloned().collect());
+            self.data.pwm = ctlpins.stored_pwm().map(|(pin, hz, duty)| (pin, hz, duty, ctlpins.stored_outcome(pin)));
+            self.data.capture = capture.lock(|capture| {
+                (capture.state(), capture.rate(), capture.len() as u16, capture.trigger_index().map(|i| i as u16))
+            });
+        }
+    }
+
+    // records the error of an action, the host reads it with ReadKey::Error
+    fn report<E: fmt::Display>(&mut self, result: core::result::Result<(), E>) {
+        if let Err(e) = result {
+            self.data.error.clear();
+            write!(self.data.error, "{}", e).ok();
+        }
+    }
+
+    // like report, for a stored power sequence which fell back to switching
+    // the power directly
+    fn report_stored(&mut self, result: core::result::Result<Option<ParseError>, SequenceError>) {
+        match result {
+            Ok(Some(e)) => {
+                self.data.error.clear();
+                write!(self.data.error, "invalid stored sequence, {}, switched the power directly", e).ok();
+            }
+            result => self.report(result.map(|_| ())),
         }
     }
 }
@@ -251,9 +612,20 @@ impl<B: UsbBus> UsbClass<B> for ControlClass {
     /// Handles control transfer IN requests from the host.
     ///
     /// This function processes various vendor-specific requests, such as:
-    /// - Retrieving configuration settings for the device (name, tags, USB console, power settings).
-    /// - Providing information about the current power state, voltage, and current readings.
+    /// - Retrieving configuration settings for the device (name, tags, USB console, power settings, pin aliases,
+    ///   pin states at boot, I2C pins).
+    /// - Providing information about the current power state, voltage, and current readings, and the
+    ///   energy used since the last reset of the energy counter.
+    /// - Reporting whether a power sequence is in progress.
     /// - Responding with the device's version information.
+    /// - Listing the stored sequences, one slot per request.
+    /// - Reporting the state set on a control pin, whether it is deferred until the DUT is powered on,
+    ///   and its sampled input level.
+    /// - Reading the edges captured on the control pins and the power enable, one per request.
+    /// - Reporting the state of a capture of the control pins, and the samples fetched from it.
+    /// - Reporting the PWM driven on a control pin.
+    /// - Reporting the result of the last I2C transfer or scan, with the bytes read or the addresses found.
+    /// - Reporting why the last action failed, as text, i.e. an invalid sequence to store or run.
     ///
     /// The function checks the request type and recipient, and parses the
     /// request value to determine which data to send back to the host.
@@ -288,19 +660,165 @@ impl<B: UsbBus> UsbClass<B> for ControlClass {
                             xfer.accept_with(&cfg.usb_console).ok();
                         }
                         ConfigKey::PowerOn => {
-                            xfer.accept_with(&cfg.power_on).ok();
+                            xfer.accept_with(&self.data.sequences.power_on).ok();
                         }
                         ConfigKey::PowerOff => {
-                            xfer.accept_with(&cfg.power_off).ok();
+                            xfer.accept_with(&self.data.sequences.power_off).ok();
                         }
                         ConfigKey::PowerRescue => {
-                            xfer.accept_with(&cfg.power_rescue).ok();
+                            xfer.accept_with(&self.data.sequences.power_rescue).ok();
+                        }
+                        ConfigKey::AliasReset => {
+                            xfer.accept_with(cfg.alias(Pin::Reset)).ok();
+                        }
+                        ConfigKey::AliasA => {
+                            xfer.accept_with(cfg.alias(Pin::A)).ok();
                         }
+                        ConfigKey::AliasB => {
+                            xfer.accept_with(cfg.alias(Pin::B)).ok();
+                        }
+                        ConfigKey::AliasC => {
+                            xfer.accept_with(cfg.alias(Pin::C)).ok();
+                        }
+                        ConfigKey::AliasD => {
+                            xfer.accept_with(cfg.alias(Pin::D)).ok();
+                        }
+                        ConfigKey::BootReset => {
+                            xfer.accept_with(&[SetPinState::from(cfg.boot_state(Pin::Reset)) as u8]).ok();
+                        }
+                        ConfigKey::BootA => {
+                            xfer.accept_with(&[SetPinState::from(cfg.boot_state(Pin::A)) as u8]).ok();
+                        }
+                        ConfigKey::BootB => {
+                            xfer.accept_with(&[SetPinState::from(cfg.boot_state(Pin::B)) as u8]).ok();
+                        }
+                        ConfigKey::BootC => {
+                            xfer.accept_with(&[SetPinState::from(cfg.boot_state(Pin::C)) as u8]).ok();
+                        }
+                        ConfigKey::BootD => {
+                            xfer.accept_with(&[SetPinState::from(cfg.boot_state(Pin::D)) as u8]).ok();
+                        }
+                        ConfigKey::I2c => {
+                            match cfg.i2c_pins() {
+                                Some((scl, sda)) => {
+                                    let pins = [sequence::pin_index(scl) as u8, sequence::pin_index(sda) as u8];
+                                    xfer.accept_with(&pins).ok();
+                                }
+                                None => {
+                                    xfer.accept_with(&[]).ok();
+                                }
+                            }
+                        }
+                    }
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
+            Ok(ControlRequest::Sequence) => {
+                // stored sequence by slot index, as name, a space and the sequence
+                if let Some(entry) = self.data.sequences.sequences.get(req.value as usize) {
+                    let mut buf = heapless::Vec::<u8, MAX_SEQUENCE_ENTRY_LENGTH>::new();
+                    if !entry.is_empty() && (buf.extend_from_slice(entry.name()).is_err()
+                                             || buf.push(b' ').is_err()
+                                             || buf.extend_from_slice(entry.sequence()).is_err()) {
+                        xfer.reject().unwrap();
+                    } else {
+                        xfer.accept_with(&buf).ok();
                     }
                 } else {
                     xfer.reject().unwrap();
                 }
             }
+            Ok(ControlRequest::EdgeLog) => {
+                // the edge at index value as of the last refresh: the time since boot in
+                // microseconds as a little endian u64, with a 100us resolution, the line
+                // (SetPin, or 5 for the power enable) and 1 for a rising edge, no data
+                // past the last edge
+                match self.data.edges.get(req.value as usize) {
+                    Some(edge) => {
+                        let mut buf = [0u8; 10];
+                        buf[..8].copy_from_slice(&edge.us.to_le_bytes());
+                        buf[8] = match edge.line {
+                            Line::Pin(pin) => sequence::pin_index(pin) as u8,
+                            Line::Power => PINS.len() as u8,
+                        };
+                        buf[9] = edge.rising as u8;
+                        xfer.accept_with(&buf).ok();
+                    }
+                    None => {
+                        xfer.accept_with(&[]).ok();
+                    }
+                }
+            }
+            Ok(ControlRequest::Capture) => {
+                match req.value.try_into() {
+                    Ok(CaptureKey::Status) => {
+                        let (state, rate, len, trigger) = self.data.capture;
+                        let mut buf = [0u8; 9];
+                        buf[0] = state as u8;
+                        buf[1..5].copy_from_slice(&rate.to_le_bytes());
+                        buf[5..7].copy_from_slice(&len.to_le_bytes());
+                        buf[7..9].copy_from_slice(&trigger.unwrap_or(0xffff).to_le_bytes());
+                        xfer.accept_with(&buf).ok();
+                    }
+                    Ok(CaptureKey::Samples) => {
+                        xfer.accept_with(&self.data.samples).ok();
+                    }
+                    Err(_) => {
+                        xfer.reject().unwrap();
+                    }
+                }
+            }
+            Ok(ControlRequest::Pwm) => {
+                // the PWM on the pin as of the last refresh: the frequency in Hz as a
+                // little endian u32, the duty cycle in %, and 1 if it is deferred until
+                // the DUT is powered on, no data if the pin has no PWM
+                match (TryInto::<SetPin>::try_into(req.value), self.data.pwm) {
+                    (Ok(pin), Some((pwm_pin, hz, duty, outcome))) if Pin::from(pin) == pwm_pin => {
+                        let mut buf = [0u8; 6];
+                        buf[..4].copy_from_slice(&hz.to_le_bytes());
+                        buf[4] = duty;
+                        buf[5] = (outcome == SetOutcome::Deferred) as u8;
+                        xfer.accept_with(&buf).ok();
+                    }
+                    (Ok(_), _) => {
+                        xfer.accept_with(&[]).ok();
+                    }
+                    (Err(_), _) => {
+                        xfer.reject().unwrap();
+                    }
+                }
+            }
+            Ok(ControlRequest::I2c) => {
+                // the result of the last I2C action: 0 on success or the I2cError
+                // plus 1, followed by the bytes read, or by the addresses found by
+                // a scan, no data if no action was made
+                match &self.data.i2c {
+                    Some((result, data)) => {
+                        let mut buf = [0u8; 1 + MAX_READ_LENGTH];
+                        buf[0] = match result {
+                            Ok(()) => 0,
+                            Err(e) => *e as u8 + 1,
+                        };
+                        buf[1..1 + data.len()].copy_from_slice(data);
+                        xfer.accept_with(&buf[..1 + data.len()]).ok();
+                    }
+                    None => {
+                        xfer.accept_with(&[]).ok();
+                    }
+                }
+            }
+            Ok(ControlRequest::Get) => {
+                // three bytes: the state set on the pin as a SetPinState, the input level,
+                // and 1 if the state is deferred until the DUT is powered on, 0 if applied
+                if let Ok(pin) = TryInto::<SetPin>::try_into(req.value) {
+                    let (state, level, outcome) = self.data.pins[pin as usize];
+                    let deferred = outcome == SetOutcome::Deferred;
+                    xfer.accept_with(&[SetPinState::from(state) as u8, level as u8, deferred as u8]).ok();
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
             Ok(ControlRequest::Read) => {
                 if let Ok(key) = req.value.try_into() {
                     match key {
@@ -329,6 +847,24 @@ impl<B: UsbBus> UsbClass<B> for ControlClass {
                             write!(buf, "{:.2}A", self.data.current).ok();
                             xfer.accept_with(&buf).ok();
                         }
+                        ReadKey::Energy => {
+                            let (joules, us) = self.data.energy;
+                            let mut buf = heapless::Vec::<u8, MAX_READ_LENGTH>::new();
+                            write!(buf, "{:.2}J {:.4}Wh {:.1}s", joules, joules / JOULES_PER_WH, us as f64 / 1_000_000.0).ok();
+                            xfer.accept_with(&buf).ok();
+                        }
+                        ReadKey::Sequence => {
+                            let state: &[u8] = match self.data.sequence {
+                                SequenceState::Idle => b"idle",
+                                SequenceState::Running => b"running",
+                                SequenceState::Aborted => b"aborted",
+                                SequenceState::TimedOut => b"timeout",
+                            };
+                            xfer.accept_with(state).ok();
+                        }
+                        ReadKey::Error => {
+                            xfer.accept_with(&self.data.error).ok();
+                        }
                     }
                 } else {
                     xfer.reject().unwrap();
@@ -343,10 +879,18 @@ impl<B: UsbBus> UsbClass<B> for ControlClass {
     ///
     /// This function processes various vendor-specific requests, such as:
     /// - Refreshing the data from the power meter.
-    /// - Setting the power state (on, off, force on/off, or rescue).
+    /// - Setting the power state (on, off, force on/off, or rescue), or aborting a running sequence.
     /// - Managing storage actions (off, connect to host, or DUT).
-    /// - Configuring device settings (name, tags, USB console, power settings).
-    /// - Setting the state of control pins (Reset, A, B, C, D).
+    /// - Resetting the energy counter of the power meter.
+    /// - Configuring device settings (name, tags, USB console, power settings, pin aliases, pin states at boot,
+    ///   I2C pins).
+    /// - Setting the state of control pins (Reset, A, B, C, D), one at a time or several together.
+    /// - Pulsing a control pin to a state for a number of microseconds.
+    /// - Driving a control pin with a PWM.
+    /// - Making a transfer or a scan with the I2C master on the control pins.
+    /// - Starting, stopping or clearing the capture of edges on the control pins.
+    /// - Starting or stopping a sampled capture of the control pins, and fetching its samples.
+    /// - Running, storing or deleting named sequences.
     ///
     /// The function checks the request type and recipient, and parses the
     /// request value to determine the action to be taken. Appropriate
@@ -368,6 +912,11 @@ impl<B: UsbBus> UsbClass<B> for ControlClass {
             _ => return,
         }
 
+        // a new action clears the error of the previous one, see ReadKey::Error
+        if req.request != ControlRequest::Refresh as u8 {
+            self.data.error.clear();
+        }
+
         match req.request.try_into() {
             Ok(ControlRequest::Refresh) => {
                 self.refresh = Some(());
@@ -389,6 +938,14 @@ impl<B: UsbBus> UsbClass<B> for ControlClass {
                     xfer.reject().unwrap();
                 }
             }
+            Ok(ControlRequest::Meter) => {
+                if let Ok(action) = req.value.try_into() {
+                    self.meter = Some(action);
+                    xfer.accept().unwrap();
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
             Ok(ControlRequest::Config) => {
                 if let Ok(key) = req.value.try_into() {
                     self.config = Some((key, heapless::Vec::from_slice(xfer.data()).unwrap()));
@@ -397,6 +954,101 @@ impl<B: UsbBus> UsbClass<B> for ControlClass {
                     xfer.reject().unwrap();
                 }
             }
+            Ok(ControlRequest::Sequence) => {
+                match (req.value.try_into(), heapless::Vec::from_slice(xfer.data())) {
+                    (Ok(action), Ok(value)) => {
+                        self.sequence = Some((action, value));
+                        xfer.accept().unwrap();
+                    }
+                    _ => xfer.reject().unwrap(),
+                }
+            }
+            Ok(ControlRequest::Pulse) => {
+                // data: the state as a SetPinState followed by the duration in
+                // microseconds as a little endian u32, a pin which would
+                // back-power the DUT floats instead, reported as an error
+                let data = xfer.data();
+                let state = data.first().cloned().map(TryInto::<SetPinState>::try_into);
+                match (req.value.try_into(), state, data.get(1..5)) {
+                    (Ok(pin), Some(Ok(state)), Some(us)) if data.len() == 5 => {
+                        let us = u32::from_le_bytes(us.try_into().unwrap());
+                        self.pulse = Some((pin, state, us));
+                        xfer.accept().unwrap();
+                    }
+                    _ => {
+                        xfer.reject().unwrap();
+                    }
+                }
+            }
+            Ok(ControlRequest::SetPins) => {
+                // value: a mask of the pins to set, bit n for SetPin n, data: a
+                // SetPinState for each pin in the mask, in SetPin order
+                let mut states: PinStates = [None; 5];
+                let mut data = xfer.data().iter();
+                let mut valid = req.value != 0 && req.value < 1 << PINS.len();
+                for (bit, state) in states.iter_mut().enumerate() {
+                    if valid && req.value & (1 << bit) != 0 {
+                        match data.next().cloned().map(TryInto::<SetPinState>::try_into) {
+                            Some(Ok(s)) => *state = Some(PinState::from(s)),
+                            _ => valid = false,
+                        }
+                    }
+                }
+                if valid && data.next().is_none() {
+                    self.pins = Some(states);
+                    xfer.accept().unwrap();
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
+            Ok(ControlRequest::Capture) => {
+                if let Ok(action) = req.value.try_into() {
+                    self.capture = Some((action, heapless::Vec::from_slice(xfer.data()).unwrap()));
+                    xfer.accept().unwrap();
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
+            Ok(ControlRequest::EdgeLog) => {
+                if let Ok(action) = req.value.try_into() {
+                    let mask = xfer.data().first().cloned().unwrap_or(0);
+                    self.edge_log = Some((action, mask));
+                    xfer.accept().unwrap();
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
+            Ok(ControlRequest::Pwm) => {
+                // data: the frequency in Hz as a little endian u32 and the duty cycle in %
+                let data = xfer.data();
+                match (req.value.try_into(), data.get(0..4), data.get(4)) {
+                    (Ok(pin), Some(hz), Some(duty)) if data.len() == 5 => {
+                        self.pwm = Some((pin, u32::from_le_bytes(hz.try_into().unwrap()), *duty));
+                        xfer.accept().unwrap();
+                    }
+                    _ => {
+                        xfer.reject().unwrap();
+                    }
+                }
+            }
+            Ok(ControlRequest::I2c) => {
+                let data = xfer.data();
+                let valid = match req.value.try_into() {
+                    Ok(I2cAction::Scan) => true,
+                    Ok(I2cAction::Transfer) => match data {
+                        [addr, len, write @ ..] => *addr <= MAX_ADDRESS && *len as usize <= MAX_I2C_LEN
+                                                   && write.len() <= MAX_I2C_LEN,
+                        _ => false,
+                    },
+                    Err(_) => false,
+                };
+                if valid {
+                    self.i2c = Some((req.value.try_into().unwrap(), heapless::Vec::from_slice(data).unwrap()));
+                    xfer.accept().unwrap();
+                } else {
+                    xfer.reject().unwrap();
+                }
+            }
             Ok(ControlRequest::Set) => {
                 if let Ok(key) = req.value.try_into() {
                     if let Some(Ok(state)) = xfer
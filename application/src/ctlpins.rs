@@ -1,46 +1,117 @@
 
-use stm32f4xx_hal::gpio::{self,DynamicPin};
+use core::fmt::Write;
+
+use arrayvec::ArrayString;
+use heapless::Vec;
+use stm32f4xx_hal::{gpio::{self,DynamicPin}, pac};
 use embedded_hal::digital::OutputPin;
 
-// create an enum with 3 possibilities: High, Low, and Floating
-// this is used to set the CTL pins to a specific state
-#[derive(Copy, Clone)]
-pub enum PinState {
-    High,
-    Low,
-    Floating,
-}
+use crate::i2c::{self, I2cError, I2cLines};
+use crate::powermeter::PowerMeter;
+use crate::storage::StorageSwitchTrait;
+use crate::pwm::{self, Pwm, PwmError};
+use crate::sequence::{self, Aliases, Matcher, ParseError, Pin, PinStates, Program, Quantity, SequenceError,
+                      Step, StorageTarget, MAX_NESTING, NO_ALIASES, PINS};
+
+// the states of a CTL pin are defined with the sequences, which set them
+pub use crate::sequence::PinState;
+
+// the power_on/power_off sequences are parsed by the sequence module, see
+// sequence.rs for a description of the format. They are executed step by step
+// by poll_sequence, which is called from an RTIC task scheduled on every wait
+// step, so the firmware keeps serving USB while a sequence is running.
+
+// period at which steps waiting for a condition are re-evaluated
+const CONDITION_POLL_US: u32 = 10_000;
+
+// steps executed in a single poll_sequence call before yielding, so a repeat
+// block without waits does not keep the CPU from serving USB
+const STEPS_PER_POLL: usize = 32;
 
-// the power_on/power_off sequences processed by _run_sequence
-// have the following format:
-// coma separated orders which could be:
-// ord[,ord]*
-// where ord is:
-//   - a,b,c,d,r followed by a state: h,l,z
-//   - w followed by a natural number, which is the number of 100ms to wait
-//   - p followed by 0 or 1, which is the desired power state
-//  , is ignored and used as a visual separator of orders
-//
-// i.e. assuming A=REC , B=POWER_BTN for a jetson board, we could have:
-//
-//   enter flashing mode:
-//   "p1,aL,rL,w1,rZ,w1"  => Power on, REC low, reset LOW, wait 100ms, reset HiZ, wait 100ms
-//
-//   power off via signal:
-//   "p1,bL,w110,bZ" => Power on, POWER_BTN low, wait 11s, POWER_BTN HiZ
-//
-//   power on via signal:
-//   "p1,bL,w5,bZ" => Power on, POWER_BTN low, wait 500ms, POWER_BTN HiZ
+// trace lines waiting to be printed, drained after every poll_sequence, so it
+// holds the lines of the steps of a whole poll and the end of the sequence
+const TRACE_LINE_LEN: usize = 96;
+const TRACE_LOG_LEN: usize = (STEPS_PER_POLL + 1) * TRACE_LINE_LEN;
 
 pub trait CTLPinsTrait {
-    fn set_ctl_a(&mut self, state:PinState);
-    fn set_ctl_b(&mut self, state:PinState);
-    fn set_ctl_c(&mut self, state:PinState);
-    fn set_ctl_d(&mut self, state:PinState);
-    fn set_reset(&mut self, state:PinState);
-    fn power_on(&mut self, on_seq: &[u8]);
-    fn power_off(&mut self, off_seq: &[u8]);
+    fn set_ctl_a(&mut self, state:PinState) -> SetOutcome;
+    fn set_ctl_b(&mut self, state:PinState) -> SetOutcome;
+    fn set_ctl_c(&mut self, state:PinState) -> SetOutcome;
+    fn set_ctl_d(&mut self, state:PinState) -> SetOutcome;
+    fn set_reset(&mut self, state:PinState) -> SetOutcome;
+    fn set_pins(&mut self, states: &PinStates) -> [Option<SetOutcome>; 5];
+    fn set_pwm(&mut self, pin: Pin, hz: u32, duty: u8) -> Result<SetOutcome, PwmError>;
+    fn stored_pwm(&self) -> Option<(Pin, u32, u8)>;
+    fn i2c_transfer(&mut self, scl: Pin, sda: Pin, addr: u8, write: &[u8], read: &mut [u8]) -> Result<(), I2cError>;
+    fn i2c_scan(&mut self, scl: Pin, sda: Pin, found: &mut dyn FnMut(u8)) -> Result<(), I2cError>;
+    fn power_on(&mut self, on_seq: &[u8]) -> Result<(), SequenceError>;
+    fn power_off(&mut self, off_seq: &[u8]) -> Result<(), SequenceError>;
+    /// Powers the DUT on or off with a sequence stored in the config. A stored
+    /// sequence which no longer parses, i.e. it uses an alias removed since,
+    /// falls back to switching the power directly, and its error is returned
+    /// once the power has been switched.
+    fn power_stored(&mut self, on: bool, seq: &[u8]) -> Result<Option<ParseError>, SequenceError> {
+        let result = if on { self.power_on(seq) } else { self.power_off(seq) };
+        match result {
+            Ok(()) => Ok(None),
+            Err(SequenceError::Invalid(e)) => {
+                if on { self.power_on(&[])? } else { self.power_off(&[])? }
+                Ok(Some(e))
+            }
+            Err(e) => Err(e),
+        }
+    }
+    fn run_sequence(&mut self, seq: &[u8]) -> Result<(), SequenceError>;
+    fn trace_sequence(&mut self, seq: &[u8], dry_run: bool) -> Result<(), SequenceError>;
+    fn pulse(&mut self, pin: Pin, state: PinState, us: u32) -> Result<SetOutcome, SequenceError>;
+    fn sequence_state(&self) -> SequenceState;
+    fn abort_sequence(&mut self) -> bool;
+    fn is_on(&self) -> bool;
+    fn stored_state(&self, pin: Pin) -> PinState;
+    fn stored_outcome(&self, pin: Pin) -> SetOutcome;
+    fn input_level(&self, pin: Pin) -> bool;
+    fn set_aliases(&mut self, aliases: Aliases);
+}
+
+// result of setting a pin outside of a sequence, states that would back-power
+// the DUT are stored without touching the pin until the DUT is powered on
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SetOutcome {
+    Applied,
+    Deferred,
+}
 
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SequenceState {
+    Idle,
+    Running,
+    Aborted,  // the last sequence was aborted before it finished
+    TimedOut, // the last sequence was aborted because a condition timed out
+}
+
+// a sequence in progress, pc is the index of the next step to execute
+struct RunningSequence {
+    program: Program,
+    pc: usize,
+    on_finish: Option<bool>, // DUT power state once the sequence has finished, if any
+    deadline: Option<u64>,   // timeout of the condition the current step waits for
+    matcher: Option<Matcher>,
+    trace: Option<Trace>,
+    loops: Vec<u16, MAX_NESTING>, // iterations left for the repeat blocks being executed
+}
+
+// a sequence started by trace_sequence logs every step it executes
+struct Trace {
+    dry_run: bool,         // pins, power, storage and the DUT serial port are not touched, steps are only logged
+    start_ms: Option<u64>, // time of the first poll, trace timestamps are relative to it
+}
+
+// result of executing one step
+enum Progress {
+    Next,       // continue with the next step
+    Sleep(u32), // continue with the next step after the given microseconds
+    Poll,       // the step waits for a condition, try again later
+    TimedOut,
 }
 
 pub struct CTLPins<PWPin>
@@ -58,6 +129,16 @@ where
     stored_reset: PinState,
     power: PWPin,
     on: bool,
+    sequence: Option<RunningSequence>,
+    started: bool,
+    last_result: SequenceState,
+    trace_log: ArrayString<TRACE_LOG_LEN>,
+    trace_lost: u32, // trace lines which did not fit in trace_log
+    aliases: Aliases, // pin aliases from the config, accepted by the sequences
+    pwm: Pwm,
+    pwm_pin: Option<Pin>,                // pin driven by the PWM right now
+    stored_pwm: Option<(Pin, u32, u8)>,  // PWM set with set_pwm, frequency and duty cycle
+    i2c_half_period: u32,                // half a period of the I2C clock in CPU cycles
 }
 
 impl<PWPin> CTLPins<PWPin>
@@ -69,20 +150,32 @@ where
                ctl_c:DynamicPin<'A', 7>,
                ctl_d:DynamicPin<'A', 8>,
                reset:DynamicPin<'A', 9>,
-               power:PWPin) -> Self {
+               power:PWPin,
+               pwm:Pwm,
+               sysclk_hz: u32,
+               boot_states: [PinState; 5]) -> Self {
         let mut instance = Self{ctl_a, stored_a: PinState::Floating,
                                 ctl_b, stored_b: PinState::Floating,
                                 ctl_c, stored_c: PinState::Floating,
                                 ctl_d, stored_d: PinState::Floating,
                                 reset, stored_reset: PinState::Floating,
-                                power, on: false};
-        instance.set_ctl_a(PinState::Floating);
-        instance.set_ctl_b(PinState::Floating);
-        instance.set_ctl_c(PinState::Floating);
-        instance.set_ctl_d(PinState::Floating);
-        instance.set_reset(PinState::Floating);
+                                power, on: false,
+                                sequence: None, started: false,
+                                last_result: SequenceState::Idle,
+                                trace_log: ArrayString::new(), trace_lost: 0,
+                                aliases: NO_ALIASES,
+                                pwm, pwm_pin: None, stored_pwm: None,
+                                i2c_half_period: i2c::half_period_cycles(sysclk_hz)};
+        // the boot states (in PINS order) are stored like set_ctl_* does, so the
+        // ones which are not off_tolerant only apply once the DUT is powered on
+        let [reset_state, a_state, b_state, c_state, d_state] = boot_states;
+        instance.set_ctl_a(a_state);
+        instance.set_ctl_b(b_state);
+        instance.set_ctl_c(c_state);
+        instance.set_ctl_d(d_state);
+        instance.set_reset(reset_state);
         let empty: [u8; 0] = [];
-        instance.power_off(&empty);
+        instance.power_off(&empty).ok();
         instance
     }
 
@@ -91,6 +184,9 @@ where
             PinState::High      => self.ctl_a.make_push_pull_output_in_state(gpio::PinState::High),
             PinState::Low       => self.ctl_a.make_push_pull_output_in_state(gpio::PinState::Low),
             PinState::Floating  => self.ctl_a.make_floating_input(),
+            PinState::OpenDrainLow => self.ctl_a.make_open_drain_output_in_state(gpio::PinState::Low),
+            PinState::PullUp    => self.ctl_a.make_pull_up_input(),
+            PinState::PullDown  => self.ctl_a.make_pull_down_input(),
         }
     }
 
@@ -99,6 +195,9 @@ where
             PinState::High      => self.ctl_b.make_push_pull_output_in_state(gpio::PinState::High),
             PinState::Low       => self.ctl_b.make_push_pull_output_in_state(gpio::PinState::Low),
             PinState::Floating  => self.ctl_b.make_floating_input(),
+            PinState::OpenDrainLow => self.ctl_b.make_open_drain_output_in_state(gpio::PinState::Low),
+            PinState::PullUp    => self.ctl_b.make_pull_up_input(),
+            PinState::PullDown  => self.ctl_b.make_pull_down_input(),
         }
     }
 
@@ -107,6 +206,9 @@ where
             PinState::High      => self.ctl_c.make_push_pull_output_in_state(gpio::PinState::High),
             PinState::Low       => self.ctl_c.make_push_pull_output_in_state(gpio::PinState::Low),
             PinState::Floating  => self.ctl_c.make_floating_input(),
+            PinState::OpenDrainLow => self.ctl_c.make_open_drain_output_in_state(gpio::PinState::Low),
+            PinState::PullUp    => self.ctl_c.make_pull_up_input(),
+            PinState::PullDown  => self.ctl_c.make_pull_down_input(),
         }
     }
 
@@ -115,6 +217,9 @@ where
             PinState::High      => self.ctl_d.make_push_pull_output_in_state(gpio::PinState::High),
             PinState::Low       => self.ctl_d.make_push_pull_output_in_state(gpio::PinState::Low),
             PinState::Floating  => self.ctl_d.make_floating_input(),
+            PinState::OpenDrainLow => self.ctl_d.make_open_drain_output_in_state(gpio::PinState::Low),
+            PinState::PullUp    => self.ctl_d.make_pull_up_input(),
+            PinState::PullDown  => self.ctl_d.make_pull_down_input(),
         }
     }
 
@@ -123,10 +228,14 @@ where
             PinState::High      => self.reset.make_push_pull_output_in_state(gpio::PinState::High),
             PinState::Low       => self.reset.make_push_pull_output_in_state(gpio::PinState::Low),
             PinState::Floating  => self.reset.make_floating_input(),
+            PinState::OpenDrainLow => self.reset.make_open_drain_output_in_state(gpio::PinState::Low),
+            PinState::PullUp    => self.reset.make_pull_up_input(),
+            PinState::PullDown  => self.reset.make_pull_down_input(),
         }
     }
 
     fn _float_all(&mut self) {
+        self._stop_pwm();
         self._set_ctl_a(PinState::Floating);
         self._set_ctl_b(PinState::Floating);
         self._set_ctl_c(PinState::Floating);
@@ -135,90 +244,541 @@ where
     }
 
     fn _float_not_off_tolerant(&mut self) {
-        if !off_tolerant(self.stored_a) {
-            self._set_ctl_a(PinState::Floating);
+        // a PWM drives its pin high, so the pin floats like a High state, even
+        // for a PWM started by a sequence
+        let pwm_pin = self._stop_pwm();
+        for pin in PINS {
+            if pwm_pin == Some(pin) || !self._stored_off_tolerant(pin) {
+                self._set_pin(pin, PinState::Floating);
+            }
+        }
+    }
+
+    // whether the stored state of a pin can be applied while the DUT is off,
+    // which is not the case of a stored PWM
+    fn _stored_off_tolerant(&self, pin: Pin) -> bool {
+        off_tolerant(self.stored_state(pin)) && self.stored_pwm.map_or(true, |(p, _, _)| p != pin)
+    }
+
+    fn _set_pin(&mut self, pin: Pin, state: PinState) {
+        if self.pwm_pin == Some(pin) {
+            self._stop_pwm();
         }
-        if !off_tolerant(self.stored_b) {
-            self._set_ctl_b(PinState::Floating);
+        match pin {
+            Pin::A     => self._set_ctl_a(state),
+            Pin::B     => self._set_ctl_b(state),
+            Pin::C     => self._set_ctl_c(state),
+            Pin::D     => self._set_ctl_d(state),
+            Pin::Reset => self._set_reset(state),
         }
-        if !off_tolerant(self.stored_c) {
-            self._set_ctl_c(PinState::Floating);
+    }
+
+    // sets several pins with a single write of their output levels: the pins
+    // becoming outputs are first switched to output mode driving the level
+    // they read, so the mode change is not a transition, then all the levels
+    // are written at once through BSRR, and last the inputs are released
+    fn _set_pins(&mut self, states: &PinStates) {
+        let idr = unsafe { (*pac::GPIOA::ptr()).idr.read().bits() };
+        let mut bsrr = 0;
+        for (pin, state) in PINS.iter().zip(states) {
+            let state = match *state {
+                Some(state) if is_output(state) => state,
+                _ => continue,
+            };
+            if self.pwm_pin == Some(*pin) {
+                self._stop_pwm();
+            }
+            let bit = gpio_bit(*pin);
+            let level = if idr & bit != 0 { gpio::PinState::High } else { gpio::PinState::Low };
+            match pin {
+                Pin::A     => prepare_output(&mut self.ctl_a, state, level),
+                Pin::B     => prepare_output(&mut self.ctl_b, state, level),
+                Pin::C     => prepare_output(&mut self.ctl_c, state, level),
+                Pin::D     => prepare_output(&mut self.ctl_d, state, level),
+                Pin::Reset => prepare_output(&mut self.reset, state, level),
+            }
+            // the upper half of BSRR resets the pins
+            bsrr |= if state == PinState::High { bit } else { bit << 16 };
+        }
+        unsafe { (*pac::GPIOA::ptr()).bsrr.write(|w| w.bits(bsrr)) };
+        for (pin, state) in PINS.iter().zip(states) {
+            match *state {
+                Some(state) if !is_output(state) => self._set_pin(*pin, state),
+                _ => {}
+            }
+        }
+    }
+
+    // switches a pin to an open-drain output released high, for the I2C lines
+    fn _release_open_drain(&mut self, pin: Pin) {
+        if self.pwm_pin == Some(pin) {
+            self._stop_pwm();
+        }
+        match pin {
+            Pin::A     => self.ctl_a.make_open_drain_output_in_state(gpio::PinState::High),
+            Pin::B     => self.ctl_b.make_open_drain_output_in_state(gpio::PinState::High),
+            Pin::C     => self.ctl_c.make_open_drain_output_in_state(gpio::PinState::High),
+            Pin::D     => self.ctl_d.make_open_drain_output_in_state(gpio::PinState::High),
+            Pin::Reset => self.reset.make_open_drain_output_in_state(gpio::PinState::High),
+        }
+    }
+
+    // runs the I2C transfers of f on the pins, which are only borrowed for
+    // them, they return to their stored states, or PWM, once it is done
+    fn _i2c_session<F>(&mut self, scl: Pin, sda: Pin, f: F) -> Result<(), I2cError>
+    where
+        F: FnOnce(&mut I2cBus<'_, PWPin>) -> Result<(), I2cError>,
+    {
+        if scl == sda {
+            return Err(I2cError::SamePin);
+        }
+        // the bus is pulled up by the DUT, and driving it while the DUT is off
+        // could back-power it
+        if !self.on {
+            return Err(I2cError::DutOff);
         }
-        if !off_tolerant(self.stored_d) {
-            self._set_ctl_d(PinState::Floating);
+        if self.sequence.is_some() {
+            return Err(I2cError::Busy);
         }
-        if !off_tolerant(self.stored_reset) {
-            self._set_reset(PinState::Floating);
+        self._release_open_drain(scl);
+        self._release_open_drain(sda);
+        let half_period = self.i2c_half_period;
+        let result = f(&mut I2cBus { pins: self, scl, sda, half_period });
+        self._set_pin(scl, self.stored_state(scl));
+        self._set_pin(sda, self.stored_state(sda));
+        if self.stored_pwm.map_or(false, |(p, _, _)| p == scl || p == sda) {
+            self._start_stored_pwm();
         }
+        result
     }
 
-    fn _lower(&self, ch:u8) -> u8 {
-        // ensure lowcase ascii
-        if (ch>64) && (ch<91) {
-            return ch ^0x20;
+    // sets the level of a pin switched to open-drain by _release_open_drain
+    fn _set_open_drain(&mut self, pin: Pin, high: bool) {
+        match pin {
+            Pin::A     => set_open_drain(&mut self.ctl_a, high),
+            Pin::B     => set_open_drain(&mut self.ctl_b, high),
+            Pin::C     => set_open_drain(&mut self.ctl_c, high),
+            Pin::D     => set_open_drain(&mut self.ctl_d, high),
+            Pin::Reset => set_open_drain(&mut self.reset, high),
         }
-        return ch
     }
-    fn _status_from_u8(&self, ch:u8) -> PinState {
-        match self._lower(ch) {
-            b'h' => PinState::High,
-            b'l' => PinState::Low,
-            b'z' => PinState::Floating,
-            _ => PinState::Floating,
+
+    fn _outcome(&self, state: PinState) -> SetOutcome {
+        if self.on || off_tolerant(state) {
+            SetOutcome::Applied
+        } else {
+            SetOutcome::Deferred
         }
     }
-    fn _run_sequence(&mut self, sequence: &[u8]) {
-        let mut p = 0;
-        while p + 1 < sequence.len() {
-            let pin = self._lower(sequence[p]);
 
-            p+=1;
-            match pin {
-                b'\0' => break,
-                b'a' => { self._set_ctl_a(self._status_from_u8(sequence[p])); p+=1},
-                b'b' => { self._set_ctl_b(self._status_from_u8(sequence[p])); p+=1},
-                b'c' => { self._set_ctl_c(self._status_from_u8(sequence[p])); p+=1},
-                b'd' => { self._set_ctl_d(self._status_from_u8(sequence[p])); p+=1},
-                b'r' => { self._set_reset(self._status_from_u8(sequence[p])); p+=1},
-                b'w' => p = self._wait(sequence, p),
-                b'p' => { let pw = sequence[p];
-                          p+=1;
-                          if pw == b'1' {
-                             self.power_on(&[0u8; 0])
-                          } else {
-                             self.power_off(&[0u8; 0])
-                          }
-                        }
-                b',' => {}, // ignore commas
-                _ => {}, // ignore unknown commands
+    // stores the state of a pin and applies it, unless it would back-power the DUT
+    fn _set_stored(&mut self, pin: Pin, state: PinState) -> SetOutcome {
+        self._store_state(pin, state);
+        let outcome = self._outcome(state);
+        if outcome == SetOutcome::Applied {
+            self._set_pin(pin, state);
+        }
+        outcome
+    }
+
+    // a state stored for a pin replaces the PWM stored for it, if any
+    fn _store_state(&mut self, pin: Pin, state: PinState) {
+        if self.stored_pwm.map_or(false, |(p, _, _)| p == pin) {
+            self.stored_pwm = None;
+        }
+        match pin {
+            Pin::A     => self.stored_a = state,
+            Pin::B     => self.stored_b = state,
+            Pin::C     => self.stored_c = state,
+            Pin::D     => self.stored_d = state,
+            Pin::Reset => self.stored_reset = state,
+        }
+    }
+
+    // applies the stored states before the DUT is powered on, the stored PWM
+    // is started by _power_on_now once the power is up
+    fn _restore_stored(&mut self) {
+        self._stop_pwm();
+        self._set_ctl_a(self.stored_a);
+        self._set_ctl_b(self.stored_b);
+        self._set_ctl_c(self.stored_c);
+        self._set_ctl_d(self.stored_d);
+        self._set_reset(self.stored_reset);
+    }
+
+    // drives a pin with a PWM, the pin is a push-pull output low when it stops
+    fn _start_pwm(&mut self, pin: Pin, hz: u32, duty: u8) -> Result<(), PwmError> {
+        pwm::validate(hz, duty)?;
+        self._set_pin(pin, PinState::Low);
+        self._stop_pwm();
+        self.pwm.start(pin, hz, duty)?;
+        self.pwm_pin = Some(pin);
+        Ok(())
+    }
+
+    fn _start_stored_pwm(&mut self) {
+        if let Some((pin, hz, duty)) = self.stored_pwm {
+            self._start_pwm(pin, hz, duty).ok();
+        }
+    }
+
+    // stops the PWM, its pin returns to its stored state, or floats if that
+    // would back-power the DUT, returns the pin the PWM was driving
+    fn _stop_pwm(&mut self) -> Option<Pin> {
+        let pin = self.pwm_pin.take()?;
+        self.pwm.stop();
+        let state = self.stored_state(pin);
+        if self.on || self._stored_off_tolerant(pin) {
+            self._set_pin(pin, state);
+        } else {
+            self._set_pin(pin, PinState::Floating);
+        }
+        Some(pin)
+    }
+
+    // apply the stored pin states as set_ctl_* would do
+    fn _apply_stored(&mut self) {
+        for pin in PINS {
+            let state = self.stored_state(pin);
+            if self.on || self._stored_off_tolerant(pin) {
+                self._set_pin(pin, state);
+            } else {
+                self._set_pin(pin, PinState::Floating);
+            }
+        }
+        if self.on {
+            self._start_stored_pwm();
+        }
+    }
+
+    fn _power_on_now(&mut self) {
+        self._restore_stored();
+        self.power.set_high().ok();
+        self.on = true;
+        self._start_stored_pwm();
+    }
+
+    fn _power_off_now(&mut self) {
+        // we set the control pins to floating while in power off, so power is not drawn
+        // from the output pins into the carried board
+        self._float_not_off_tolerant();
+        self.power.set_low().ok();
+        self.on = false;
+    }
+
+    fn _start_sequence(&mut self, program: Program, on_finish: Option<bool>, trace: Option<Trace>) {
+        self.sequence = Some(RunningSequence { program, pc: 0, on_finish, deadline: None, matcher: None, trace, loops: Vec::new() });
+        self.started = true;
+        self.last_result = SequenceState::Idle;
+    }
+
+    fn _abort_sequence(&mut self, result: SequenceState) -> bool {
+        let dry_run = self._dry_run();
+        if self.sequence.take().is_none() {
+            return false;
+        }
+        self.last_result = result;
+        if dry_run {
+            return true;
+        }
+        // the power pin is left as the sequence set it, and the CTL pins are
+        // returned to their stored states, floating the ones that would
+        // back-power the DUT if it is off
+        self._apply_stored();
+        true
+    }
+
+    fn _dry_run(&self) -> bool {
+        matches!(self.sequence, Some(RunningSequence { trace: Some(Trace { dry_run: true, .. }), .. }))
+    }
+
+    // log the execution of the step at pc, or the end of the sequence if step
+    // is None, when the running sequence is being traced
+    fn _trace(&mut self, step: Option<(usize, Step)>, note: &str, now_ms: u64, power_meter: &mut dyn PowerMeter) {
+        let seq = match self.sequence.as_mut() {
+            Some(seq) => seq,
+            None => return,
+        };
+        let start_ms = match seq.trace.as_mut() {
+            Some(trace) => *trace.start_ms.get_or_insert(now_ms),
+            None => return,
+        };
+        let mut line = ArrayString::<TRACE_LINE_LEN>::new();
+        write!(line, "{:>8}ms ", now_ms - start_ms).ok();
+        match step {
+            Some((pc, step)) => {
+                let mut text = ArrayString::<48>::new();
+                sequence::write_step(&mut text, &seq.program, step).ok();
+                write!(line, "#{:<3} {:<20}", pc, text.as_str()).ok();
+            }
+            None => { write!(line, "{:<25}", "end").ok(); }
+        }
+        write!(line, " {:.3}A {:.2}V{}\r\n", power_meter.get_current(), power_meter.get_voltage(), note).ok();
+        if self.trace_log.try_push_str(&line).is_err() {
+            self.trace_lost += 1;
+        }
+    }
+
+    /// Writes and clears the trace lines logged by a sequence started with
+    /// trace_sequence.
+    pub fn write_trace(&mut self, writer: &mut dyn Write) {
+        writer.write_str(&self.trace_log).ok();
+        if self.trace_lost > 0 {
+            write!(writer, "({} trace lines lost)\r\n", self.trace_lost).ok();
+        }
+        self.trace_log.clear();
+        self.trace_lost = 0;
+    }
+
+    fn _finish_sequence(&mut self, on_finish: Option<bool>) {
+        match on_finish {
+            Some(true) => {
+                self.on = true;
+                // the stored PWM runs once the DUT is on, unless it was started
+                // by the power step of the sequence, or the sequence drives a PWM
+                if self.pwm_pin.is_none() {
+                    self._start_stored_pwm();
+                }
+            }
+            Some(false) => {
+                self._float_not_off_tolerant();
+                self.on = false;
+            }
+            None => {}
+        }
+    }
+
+    /// Returns true once after a new sequence has been started, so the caller
+    /// can schedule the first poll_sequence call.
+    pub fn take_started(&mut self) -> bool {
+        let started = self.started;
+        self.started = false;
+        started
+    }
+
+    /// Feeds a byte received from the DUT serial port to the step waiting
+    /// for a serial pattern, if any.
+    pub fn feed_serial(&mut self, b: u8) {
+        if let Some(RunningSequence { matcher: Some(matcher), .. }) = self.sequence.as_mut() {
+            matcher.feed(b);
+        }
+    }
+
+    /// Executes the running sequence until the next wait step, or for at most
+    /// STEPS_PER_POLL steps, returning the time in microseconds after which it
+    /// must be polled again, or None when the sequence has finished. now_ms is
+    /// a monotonic time in milliseconds used for the timeouts of steps waiting
+    /// for a condition, send_to_dut enqueues the text of send steps for the DUT
+    /// serial port and storage is switched by the storage steps.
+    pub fn poll_sequence(&mut self, now_ms: u64, power_meter: &mut dyn PowerMeter,
+                         storage: &mut dyn StorageSwitchTrait,
+                         send_to_dut: &mut dyn FnMut(&[u8])) -> Option<u32> {
+        let mut budget = STEPS_PER_POLL;
+        while let Some(seq) = self.sequence.as_ref() {
+            if budget == 0 {
+                return Some(0);
             }
+            budget -= 1;
+            // the pc of the step is kept for the trace, as a repeat moves it
+            let pc = seq.pc;
+            let step = match seq.program.steps.get(pc) {
+                Some(step) => *step,
+                None => {
+                    let on = seq.on_finish;
+                    self._trace(None, "", now_ms, power_meter);
+                    self.sequence = None;
+                    self._finish_sequence(on);
+                    return None;
+                }
+            };
+            match self._execute(step, now_ms, power_meter, storage, send_to_dut) {
+                Progress::Next => {
+                    self._trace(Some((pc, step)), "", now_ms, power_meter);
+                    self._next_step();
+                }
+                Progress::Sleep(us) => {
+                    self._trace(Some((pc, step)), "", now_ms, power_meter);
+                    self._next_step();
+                    return Some(us);
+                }
+                Progress::Poll => return Some(CONDITION_POLL_US),
+                Progress::TimedOut => {
+                    self._trace(Some((pc, step)), " timed out", now_ms, power_meter);
+                    self._abort_sequence(SequenceState::TimedOut);
+                    return None;
+                }
+            }
+        }
+        None
+    }
+
+    fn _next_step(&mut self) {
+        if let Some(seq) = self.sequence.as_mut() {
+            seq.pc += 1;
+            seq.deadline = None;
+            seq.matcher = None;
         }
     }
 
-    fn _wait(&self, sequence: &[u8], mut p: usize) -> usize {
-        // parse for 100ms increments
-        let mut wait:u32 = 0;
-        while p < sequence.len() {
-            let ch = sequence[p];
-            if (ch < b'0') || (ch > b'9') {
-                break;
+    fn _execute(&mut self, step: Step, now_ms: u64, power_meter: &mut dyn PowerMeter,
+                storage: &mut dyn StorageSwitchTrait, send_to_dut: &mut dyn FnMut(&[u8])) -> Progress {
+        let dry_run = self._dry_run();
+        match step {
+            Step::Set(_, _) | Step::SetPins(_) | Step::Pwm(_, _, _) | Step::Power(_) | Step::Send(_) | Step::Storage(_)
+                if dry_run => {}
+            Step::Set(pin, state) => self._set_pin(pin, state),
+            Step::SetPins(states) => self._set_pins(&states),
+            Step::Pwm(pin, hz, duty) => { self._start_pwm(pin, hz, duty).ok(); }
+            Step::Wait(us) => return Progress::Sleep(us),
+            Step::Power(true) => self._power_on_now(),
+            Step::Power(false) => self._power_off_now(),
+            Step::Send(text) => {
+                let seq = self.sequence.as_ref().unwrap();
+                send_to_dut(seq.program.text(text));
+            }
+            Step::Storage(StorageTarget::Dut) => storage.connect_to_dut(),
+            Step::Storage(StorageTarget::Host) => storage.connect_to_host(),
+            Step::Storage(StorageTarget::Off) => storage.power_off(),
+            Step::Expect(text, timeout) => {
+                let seq = self.sequence.as_mut().unwrap();
+                if seq.matcher.is_none() {
+                    seq.matcher = Some(Matcher::new(seq.program.text(text)));
+                }
+                let found = seq.matcher.as_ref().map_or(false, |m| m.found());
+                return self._wait_until(found, timeout, now_ms);
+            }
+            Step::Level(pin, high, timeout) => {
+                let reached = self.input_level(pin) == high;
+                return self._wait_until(reached, timeout, now_ms);
             }
-            wait = wait * 10 + (ch - b'0') as u32;
-            p += 1;
+            Step::Above(quantity, value, timeout) => {
+                let reached = measure(power_meter, quantity) > value;
+                return self._wait_until(reached, timeout, now_ms);
+            }
+            Step::Below(quantity, value, timeout) => {
+                let reached = measure(power_meter, quantity) < value;
+                return self._wait_until(reached, timeout, now_ms);
+            }
+            Step::Repeat(end) => {
+                let seq = self.sequence.as_mut().unwrap();
+                if let Some(Step::EndRepeat(_, count)) = seq.program.steps.get(end as usize) {
+                    // the parser limits the nesting to the size of loops
+                    seq.loops.push(*count).ok();
+                }
+            }
+            Step::EndRepeat(start, _) => {
+                let seq = self.sequence.as_mut().unwrap();
+                if let Some(remaining) = seq.loops.last_mut() {
+                    *remaining -= 1;
+                    if *remaining > 0 {
+                        // _next_step moves on to the first step of the block
+                        seq.pc = start as usize;
+                    } else {
+                        seq.loops.pop();
+                    }
+                }
+            }
+        }
+        Progress::Next
+    }
+
+    // common handling for the steps waiting for a condition, the timeout
+    // starts counting on the first evaluation of the step
+    fn _wait_until(&mut self, done: bool, timeout: u32, now_ms: u64) -> Progress {
+        if done {
+            return Progress::Next;
+        }
+        let seq = self.sequence.as_mut().unwrap();
+        let deadline = *seq.deadline.get_or_insert(now_ms + (timeout / 1000) as u64);
+        if now_ms >= deadline {
+            Progress::TimedOut
+        } else {
+            Progress::Poll
         }
-        // This value has been calculated experimentally, it would need to be
-        // updated for different clock speeds
-        cortex_m::asm::delay(3_300_000 * wait);
-        p
     }
 }
 
+fn measure(power_meter: &mut dyn PowerMeter, quantity: Quantity) -> f32 {
+    match quantity {
+        Quantity::Current => power_meter.get_current(),
+        Quantity::Voltage => power_meter.get_voltage(),
+    }
+}
+
+// bit of each CTL pin in the GPIOA registers, and EXTI line
+pub fn gpio_bit(pin: Pin) -> u32 {
+    match pin {
+        Pin::A     => 1 << 5,
+        Pin::B     => 1 << 6,
+        Pin::C     => 1 << 7,
+        Pin::D     => 1 << 8,
+        Pin::Reset => 1 << 9,
+    }
+}
+
+// switches a pin to output mode driving the given level, or released if it
+// becomes open-drain, the level is changed afterwards by _set_pins
+fn prepare_output<const N: u8>(pin: &mut DynamicPin<'A', N>, state: PinState, level: gpio::PinState) {
+    match state {
+        PinState::OpenDrainLow => pin.make_open_drain_output_in_state(gpio::PinState::High),
+        _ => pin.make_push_pull_output_in_state(level),
+    }
+}
+
+fn set_open_drain<const N: u8>(pin: &mut DynamicPin<'A', N>, high: bool) {
+    if high {
+        pin.set_high().ok();
+    } else {
+        pin.set_low().ok();
+    }
+}
+
+// the I2C lines on two CTL pins switched to open-drain outputs
+struct I2cBus<'a, PWPin: OutputPin> {
+    pins: &'a mut CTLPins<PWPin>,
+    scl: Pin,
+    sda: Pin,
+    half_period: u32, // half a period of SCL in CPU cycles
+}
+
+impl<PWPin> I2cLines for I2cBus<'_, PWPin>
+where
+    PWPin: OutputPin,
+{
+    fn set_scl(&mut self, high: bool) {
+        self.pins._set_open_drain(self.scl, high);
+    }
+
+    fn set_sda(&mut self, high: bool) {
+        self.pins._set_open_drain(self.sda, high);
+    }
+
+    fn scl(&self) -> bool {
+        self.pins.input_level(self.scl)
+    }
+
+    fn sda(&self) -> bool {
+        self.pins.input_level(self.sda)
+    }
+
+    fn delay(&self) {
+        cortex_m::asm::delay(self.half_period);
+    }
+}
+
+// states in which the pin drives the line, High, Low or open-drain Low
+fn is_output(state: PinState) -> bool {
+    matches!(state, PinState::High | PinState::Low | PinState::OpenDrainLow)
+}
+
 // High output state is not ok when the board is not powered on
 // because it will draw power from the output pins into the carried board
 fn off_tolerant(state: PinState) -> bool {
     match state {
         PinState::Floating => true,
         PinState::Low => true,
+        PinState::OpenDrainLow => true,
+        PinState::PullDown => true,
         _ => false,
     }
 }
@@ -227,67 +787,187 @@ impl<PWPin> CTLPinsTrait for CTLPins<PWPin>
 where
     PWPin: OutputPin,
 {
-    fn set_ctl_a(&mut self, state:PinState) {
-        self.stored_a = state;
-        if self.on || off_tolerant(state){
-            self._set_ctl_a(state)
+    fn set_ctl_a(&mut self, state: PinState) -> SetOutcome {
+        self._set_stored(Pin::A, state)
+    }
+
+    fn set_ctl_b(&mut self, state: PinState) -> SetOutcome {
+        self._set_stored(Pin::B, state)
+    }
+
+    fn set_ctl_c(&mut self, state: PinState) -> SetOutcome {
+        self._set_stored(Pin::C, state)
+    }
+
+    fn set_ctl_d(&mut self, state: PinState) -> SetOutcome {
+        self._set_stored(Pin::D, state)
+    }
+
+    fn set_reset(&mut self, state: PinState) -> SetOutcome {
+        self._set_stored(Pin::Reset, state)
+    }
+
+    fn set_pins(&mut self, states: &PinStates) -> [Option<SetOutcome>; 5] {
+        // like set_ctl_*, the states are stored and the ones which would
+        // back-power the DUT are only applied once it is powered on, the
+        // outcome of each pin set is returned in PINS order
+        let mut applied: PinStates = [None; 5];
+        let mut outcomes = [None; 5];
+        for (i, (pin, state)) in PINS.iter().zip(states).enumerate() {
+            if let Some(state) = *state {
+                self._store_state(*pin, state);
+                let outcome = self._outcome(state);
+                if outcome == SetOutcome::Applied {
+                    applied[i] = Some(state);
+                }
+                outcomes[i] = Some(outcome);
+            }
+        }
+        self._set_pins(&applied);
+        outcomes
+    }
+
+    fn set_pwm(&mut self, pin: Pin, hz: u32, duty: u8) -> Result<SetOutcome, PwmError> {
+        pwm::validate(hz, duty)?;
+        // the pin is left low when the PWM stops, and like a High state the
+        // PWM only runs while the DUT is powered on
+        self._store_state(pin, PinState::Low);
+        self.stored_pwm = Some((pin, hz, duty));
+        if !self.on {
+            return Ok(SetOutcome::Deferred);
         }
+        self._start_pwm(pin, hz, duty)?;
+        Ok(SetOutcome::Applied)
+    }
+
+    fn stored_pwm(&self) -> Option<(Pin, u32, u8)> {
+        self.stored_pwm
+    }
+
+    fn i2c_transfer(&mut self, scl: Pin, sda: Pin, addr: u8, write: &[u8], read: &mut [u8]) -> Result<(), I2cError> {
+        self._i2c_session(scl, sda, |bus| i2c::transfer(bus, addr, write, read))
+    }
+
+    fn i2c_scan(&mut self, scl: Pin, sda: Pin, found: &mut dyn FnMut(u8)) -> Result<(), I2cError> {
+        self._i2c_session(scl, sda, |bus| i2c::scan(bus, found))
     }
 
-    fn set_ctl_b(&mut self, state: PinState) {
-        self.stored_b = state;
-        if self.on || off_tolerant(state){
-            self._set_ctl_b(state)
+    fn power_on(&mut self, on_seq: &[u8]) -> Result<(), SequenceError> {
+        // validate the whole sequence before touching any pin
+        let program = sequence::parse(on_seq, &self.aliases)?;
+        if sequence::is_empty(on_seq) {
+            // a direct power change aborts any sequence in progress
+            self._abort_sequence(SequenceState::Aborted);
+            self._power_on_now();
+            return Ok(());
         }
+        if self.sequence.is_some() {
+            return Err(SequenceError::Busy);
+        }
+        self._restore_stored();
+        self._start_sequence(program, Some(true), None);
+        Ok(())
     }
 
-    fn set_ctl_c(&mut self, state: PinState) {
-        self.stored_c = state;
-        if self.on || off_tolerant(state){
-            self._set_ctl_c(state)
+    fn power_off(&mut self, off_seq: &[u8]) -> Result<(), SequenceError> {
+        let program = sequence::parse(off_seq, &self.aliases)?;
+        if sequence::is_empty(off_seq) {
+            self._abort_sequence(SequenceState::Aborted);
+            self._power_off_now();
+            return Ok(());
+        }
+        if self.sequence.is_some() {
+            return Err(SequenceError::Busy);
         }
+        self._start_sequence(program, Some(false), None);
+        Ok(())
     }
 
+    fn run_sequence(&mut self, seq: &[u8]) -> Result<(), SequenceError> {
+        let program = sequence::parse(seq, &self.aliases)?;
+        if self.sequence.is_some() {
+            return Err(SequenceError::Busy);
+        }
+        self._start_sequence(program, None, None);
+        Ok(())
+    }
 
-    fn set_ctl_d(&mut self, state: PinState) {
-        self.stored_d = state;
-        if self.on || off_tolerant(state){
-            self._set_ctl_d(state)
+    fn trace_sequence(&mut self, seq: &[u8], dry_run: bool) -> Result<(), SequenceError> {
+        let program = sequence::parse(seq, &self.aliases)?;
+        if self.sequence.is_some() {
+            return Err(SequenceError::Busy);
         }
+        self.trace_log.clear();
+        self.trace_lost = 0;
+        self._start_sequence(program, None, Some(Trace { dry_run, start_ms: None }));
+        Ok(())
     }
 
-    fn set_reset(&mut self, state: PinState) {
-        self.stored_reset = state;
-        if self.on || off_tolerant(state){
-            self._set_reset(state)
+    fn pulse(&mut self, pin: Pin, state: PinState, us: u32) -> Result<SetOutcome, SequenceError> {
+        if self.sequence.is_some() {
+            return Err(SequenceError::Busy);
         }
+        // a pulse is run as a sequence so it is timed without blocking USB, the
+        // pin is returned to the state it had, and like set_ctl_* no pin is
+        // driven high into a DUT which is off
+        let applied = |state| if self.on || off_tolerant(state) { state } else { PinState::Floating };
+        let mut program = Program::new();
+        program.steps.push(Step::Set(pin, applied(state))).ok();
+        program.steps.push(Step::Wait(us)).ok();
+        let restore = match self.stored_pwm {
+            // the PWM stored on the pin is started again
+            Some((p, hz, duty)) if p == pin && self.on => Step::Pwm(pin, hz, duty),
+            Some((p, _, _)) if p == pin => Step::Set(pin, PinState::Floating),
+            _ => Step::Set(pin, applied(self.stored_state(pin))),
+        };
+        program.steps.push(restore).ok();
+        // a deferred pulse floats the pin for its duration
+        let outcome = if applied(state) == state { SetOutcome::Applied } else { SetOutcome::Deferred };
+        self._start_sequence(program, None, None);
+        Ok(outcome)
     }
 
-    fn power_on(&mut self, on_seq: &[u8]) {
-        self._set_ctl_a(self.stored_a);
-        self._set_ctl_b(self.stored_b);
-        self._set_ctl_c(self.stored_c);
-        self._set_ctl_d(self.stored_d);
-        self._set_reset(self.stored_reset);
-        if on_seq.len() == 0 || (on_seq.len()>0 && on_seq[0] == b'\0') {
-            self.power.set_high().ok();
+    fn sequence_state(&self) -> SequenceState {
+        if self.sequence.is_some() {
+            SequenceState::Running
         } else {
-            self._run_sequence(on_seq);
+            self.last_result
         }
-        self.on = true;
     }
 
-    fn power_off(&mut self, on_seq: &[u8]) {
-        if on_seq.len() == 0 || (on_seq.len()>0 && on_seq[0] == b'\0') {
-            // we set the control pins to floating while in power off, so power is not drawn
-            // from the output pins into the carried board
-            self._float_not_off_tolerant();
-            self.power.set_low().ok();
-        } else {
-            self._run_sequence(on_seq);
-            self._float_not_off_tolerant();
+    fn abort_sequence(&mut self) -> bool {
+        self._abort_sequence(SequenceState::Aborted)
+    }
+
+    fn is_on(&self) -> bool {
+        self.on
+    }
+
+    fn stored_state(&self, pin: Pin) -> PinState {
+        match pin {
+            Pin::A     => self.stored_a,
+            Pin::B     => self.stored_b,
+            Pin::C     => self.stored_c,
+            Pin::D     => self.stored_d,
+            Pin::Reset => self.stored_reset,
         }
-        self.on = false;
     }
-}
 
+    fn stored_outcome(&self, pin: Pin) -> SetOutcome {
+        if !self.on && self.stored_pwm.map_or(false, |(p, _, _)| p == pin) {
+            return SetOutcome::Deferred;
+        }
+        self._outcome(self.stored_state(pin))
+    }
+
+    fn input_level(&self, pin: Pin) -> bool {
+        // the input data register samples the pin in every mode, so this reads
+        // what the DUT drives on floating pins, and the actual level of outputs
+        let idr = unsafe { (*pac::GPIOA::ptr()).idr.read().bits() };
+        idr & gpio_bit(pin) != 0
+    }
+
+    fn set_aliases(&mut self, aliases: Aliases) {
+        self.aliases = aliases;
+    }
+}
@@ -0,0 +1,119 @@
+use core::iter;
+
+use heapless::Deque;
+use stm32f4xx_hal::pac;
+
+use crate::ctlpins::gpio_bit;
+use crate::sequence::{Pin, PINS};
+
+// Edges on the CTL lines are captured with the EXTI lines 5 to 9, and the power
+// enable line of the DUT (PA4) with EXTI line 4, so the log shows when the DUT
+// was powered on or off. Those lines are routed to port A by the reset value of
+// SYSCFG EXTICR, so only the EXTI peripheral is configured here.
+//
+// The interrupt handler timestamps the edges with the monotonic timer, with a
+// 100us resolution, and reads the level of the line to tell rising from falling
+// edges, so two edges closer than the interrupt latency show as a single one.
+// Edges driven by Dutlink itself on output pins are captured as well.
+
+pub const EDGE_LOG_LEN: usize = 128;
+
+// EXTI line of the power enable pin
+const POWER_BIT: u32 = 1 << 4;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Line {
+    Pin(Pin),
+    Power,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Edge {
+    pub us: u64, // time since boot in microseconds
+    pub line: Line,
+    pub rising: bool,
+}
+
+pub struct EdgeLog {
+    edges: Deque<Edge, EDGE_LOG_LEN>,
+    lost: u32,  // oldest edges dropped because the log was full
+    lines: u32, // EXTI lines being captured, 0 when stopped
+}
+
+impl EdgeLog {
+    pub fn new() -> Self {
+        EdgeLog { edges: Deque::new(), lost: 0, lines: 0 }
+    }
+
+    /// Starts capturing the edges of the pins in mask, bit n for PINS[n], and
+    /// of the power enable line. The edges already logged are kept.
+    pub fn start(&mut self, mask: u8) {
+        let lines = PINS.iter().enumerate()
+            .filter(|(i, _)| mask & (1 << i) != 0)
+            .fold(POWER_BIT, |lines, (_, pin)| lines | gpio_bit(*pin));
+        self.stop();
+        unsafe {
+            let exti = &*pac::EXTI::ptr();
+            exti.rtsr.modify(|r, w| w.bits(r.bits() | lines));
+            exti.ftsr.modify(|r, w| w.bits(r.bits() | lines));
+            // discard the edges seen before the capture started
+            exti.pr.write(|w| w.bits(lines));
+            exti.imr.modify(|r, w| w.bits(r.bits() | lines));
+        }
+        self.lines = lines;
+    }
+
+    pub fn stop(&mut self) {
+        let lines = self.lines;
+        unsafe {
+            let exti = &*pac::EXTI::ptr();
+            exti.imr.modify(|r, w| w.bits(r.bits() & !lines));
+            exti.rtsr.modify(|r, w| w.bits(r.bits() & !lines));
+            exti.ftsr.modify(|r, w| w.bits(r.bits() & !lines));
+        }
+        self.lines = 0;
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.lines != 0
+    }
+
+    pub fn clear(&mut self) {
+        self.edges.clear();
+        self.lost = 0;
+    }
+
+    /// Logs the pending edges, called from the EXTI interrupt handlers with
+    /// the current time in microseconds.
+    pub fn capture(&mut self, now_us: u64) {
+        let (pending, idr) = unsafe {
+            let exti = &*pac::EXTI::ptr();
+            let pending = exti.pr.read().bits() & self.lines;
+            exti.pr.write(|w| w.bits(pending));
+            (pending, (*pac::GPIOA::ptr()).idr.read().bits())
+        };
+        let lines = iter::once(Line::Power).chain(PINS.iter().map(|pin| Line::Pin(*pin)));
+        for line in lines {
+            let bit = match line {
+                Line::Power => POWER_BIT,
+                Line::Pin(pin) => gpio_bit(pin),
+            };
+            if pending & bit == 0 {
+                continue;
+            }
+            if self.edges.is_full() {
+                self.edges.pop_front();
+                self.lost += 1;
+            }
+            self.edges.push_back(Edge { us: now_us, line, rising: idr & bit != 0 }).ok();
+        }
+    }
+
+    pub fn edges(&self) -> impl Iterator<Item = &Edge> {
+        self.edges.iter()
+    }
+
+    pub fn lost(&self) -> u32 {
+        self.lost
+    }
+}
@@ -0,0 +1,218 @@
+use core::fmt;
+
+// I2C master bit-banged on two CTL pins, to reach an EEPROM or a PMIC of the
+// DUT in recovery situations. CTLPins switches the pins to open-drain outputs
+// and implements I2cLines on them, the bus relies on the pull-ups of the DUT,
+// so transfers are only made while the DUT is powered on.
+//
+// The clock runs at about 100kHz, with the SCL high time stretched by the
+// interrupts served in between, which I2C allows. Devices can stretch the
+// clock too, up to STRETCH_TIMEOUT half periods.
+
+pub const MAX_I2C_LEN: usize = 64;
+pub const MAX_ADDRESS: u8 = 0x7f;
+
+// addresses probed by a scan, the ones below 0x08 and above 0x77 are reserved
+const SCAN_ADDRESSES: core::ops::RangeInclusive<u8> = 0x08..=0x77;
+
+const SCL_HZ: u32 = 100_000;
+
+// half periods a device can hold SCL low, about 10ms
+const STRETCH_TIMEOUT: u32 = 2000;
+
+// clocks sent to free SDA from a device interrupted in the middle of a byte
+const RECOVERY_CLOCKS: usize = 9;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum I2cError {
+    NoPins,         // the I2C pins are not set in the config
+    DutOff,
+    Busy,           // a sequence is running, it could set the pins
+    SamePin,        // SCL and SDA are the same pin
+    InvalidAddress,
+    AddressNack,    // no device acknowledged the address
+    DataNack,       // the device did not acknowledge a written byte
+    SdaStuck,       // SDA held low, the bus could not be recovered
+    SclStuck,       // SCL held low for longer than the clock stretching timeout
+}
+
+impl fmt::Display for I2cError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            I2cError::NoPins         => write!(f, "the I2C pins are not set"),
+            I2cError::DutOff         => write!(f, "the DUT is powered off"),
+            I2cError::Busy           => write!(f, "a sequence is running"),
+            I2cError::SamePin        => write!(f, "SCL and SDA must be different pins"),
+            I2cError::InvalidAddress => write!(f, "the address must be between 0x00 and 0x{:02x}", MAX_ADDRESS),
+            I2cError::AddressNack    => write!(f, "no device acknowledged the address"),
+            I2cError::DataNack       => write!(f, "the device did not acknowledge the data"),
+            I2cError::SdaStuck       => write!(f, "SDA is held low"),
+            I2cError::SclStuck       => write!(f, "SCL is held low"),
+        }
+    }
+}
+
+// the open-drain lines of the bus, a line set high is released so the
+// pull-up or a device can set its level
+pub trait I2cLines {
+    fn set_scl(&mut self, high: bool);
+    fn set_sda(&mut self, high: bool);
+    fn scl(&self) -> bool;
+    fn sda(&self) -> bool;
+    fn delay(&self); // waits for half a period of SCL
+}
+
+/// Returns half a period of SCL in cycles of the system clock, to implement
+/// I2cLines::delay.
+pub fn half_period_cycles(sysclk_hz: u32) -> u32 {
+    sysclk_hz / SCL_HZ / 2
+}
+
+/// Writes the bytes in write to the device at addr, then reads read.len()
+/// bytes from it after a repeated start. With nothing to write nor to read
+/// only the address is sent, which tells whether the device is present.
+pub fn transfer<L: I2cLines>(lines: &mut L, addr: u8, write: &[u8], read: &mut [u8]) -> Result<(), I2cError> {
+    if addr > MAX_ADDRESS {
+        return Err(I2cError::InvalidAddress);
+    }
+    recover(lines)?;
+    let result = _transfer(lines, addr, write, read);
+    stop(lines);
+    result
+}
+
+/// Probes the addresses of SCAN_ADDRESSES in a single session on the bus,
+/// calling found with the ones a device acknowledged.
+pub fn scan<L: I2cLines>(lines: &mut L, found: &mut dyn FnMut(u8)) -> Result<(), I2cError> {
+    recover(lines)?;
+    for addr in SCAN_ADDRESSES {
+        let result = start(lines).and_then(|_| write_byte(lines, addr << 1));
+        stop(lines);
+        if result? {
+            found(addr);
+        }
+    }
+    Ok(())
+}
+
+fn _transfer<L: I2cLines>(lines: &mut L, addr: u8, write: &[u8], read: &mut [u8]) -> Result<(), I2cError> {
+    start(lines)?;
+    if !write.is_empty() || read.is_empty() {
+        if !write_byte(lines, addr << 1)? {
+            return Err(I2cError::AddressNack);
+        }
+        for b in write {
+            if !write_byte(lines, *b)? {
+                return Err(I2cError::DataNack);
+            }
+        }
+        if read.is_empty() {
+            return Ok(());
+        }
+        start(lines)?;
+    }
+    if !write_byte(lines, (addr << 1) | 1)? {
+        return Err(I2cError::AddressNack);
+    }
+    let last = read.len() - 1;
+    for (i, b) in read.iter_mut().enumerate() {
+        // the last byte is not acknowledged, so the device releases SDA for the stop
+        *b = read_byte(lines, i != last)?;
+    }
+    Ok(())
+}
+
+// releases SCL and waits for devices stretching the clock
+fn release_scl<L: I2cLines>(lines: &mut L) -> Result<(), I2cError> {
+    lines.set_scl(true);
+    for _ in 0..STRETCH_TIMEOUT {
+        if lines.scl() {
+            return Ok(());
+        }
+        lines.delay();
+    }
+    Err(I2cError::SclStuck)
+}
+
+// a device left in the middle of a read holds SDA low until it is clocked out
+fn recover<L: I2cLines>(lines: &mut L) -> Result<(), I2cError> {
+    lines.set_sda(true);
+    release_scl(lines)?;
+    lines.delay();
+    for _ in 0..RECOVERY_CLOCKS {
+        if lines.sda() {
+            return Ok(());
+        }
+        lines.set_scl(false);
+        lines.delay();
+        release_scl(lines)?;
+        lines.delay();
+    }
+    if lines.sda() {
+        Ok(())
+    } else {
+        Err(I2cError::SdaStuck)
+    }
+}
+
+// a start or repeated start condition, SDA falls while SCL is high
+fn start<L: I2cLines>(lines: &mut L) -> Result<(), I2cError> {
+    lines.set_sda(true);
+    lines.delay();
+    release_scl(lines)?;
+    lines.delay();
+    if !lines.sda() {
+        return Err(I2cError::SdaStuck);
+    }
+    lines.set_sda(false);
+    lines.delay();
+    lines.set_scl(false);
+    lines.delay();
+    Ok(())
+}
+
+// a stop condition, SDA rises while SCL is high
+fn stop<L: I2cLines>(lines: &mut L) {
+    lines.set_sda(false);
+    lines.delay();
+    release_scl(lines).ok();
+    lines.delay();
+    lines.set_sda(true);
+    lines.delay();
+}
+
+fn write_bit<L: I2cLines>(lines: &mut L, bit: bool) -> Result<(), I2cError> {
+    lines.set_sda(bit);
+    lines.delay();
+    release_scl(lines)?;
+    lines.delay();
+    lines.set_scl(false);
+    Ok(())
+}
+
+fn read_bit<L: I2cLines>(lines: &mut L) -> Result<bool, I2cError> {
+    lines.set_sda(true);
+    lines.delay();
+    release_scl(lines)?;
+    lines.delay();
+    let bit = lines.sda();
+    lines.set_scl(false);
+    Ok(bit)
+}
+
+// writes a byte MSB first, returns true if it was acknowledged
+fn write_byte<L: I2cLines>(lines: &mut L, byte: u8) -> Result<bool, I2cError> {
+    for i in (0..8).rev() {
+        write_bit(lines, byte & (1 << i) != 0)?;
+    }
+    Ok(!read_bit(lines)?)
+}
+
+fn read_byte<L: I2cLines>(lines: &mut L, ack: bool) -> Result<u8, I2cError> {
+    let mut byte = 0;
+    for _ in 0..8 {
+        byte = (byte << 1) | read_bit(lines)? as u8;
+    }
+    write_bit(lines, !ack)?;
+    Ok(byte)
+}
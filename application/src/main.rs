@@ -14,9 +14,14 @@ mod powermeter;
 mod filter;
 mod version;
 mod config;
+mod sequence;
+mod edgelog;
+mod capture;
+mod pwm;
+mod i2c;
 
 // dispatchers are free Hardware IRQs we don't use that rtic will use to dispatch
-// software tasks, we are not using EXT interrupts, so we can use those
+// software tasks, we are not using the EXTI0 to EXTI2 interrupts, so we can use those
 #[rtic::app(device = stm32f4xx_hal::pac, peripherals = true, dispatchers = [EXTI0, EXTI1, EXTI2])]
 mod app {
 
@@ -38,15 +43,20 @@ mod app {
     use usb_device::{class_prelude::*, prelude::*};
 
     use usbd_serial::SerialPort;
+    use systick_monotonic::{fugit::TimerDurationU64, Systick};
 
     use crate::{control::ControlClass, dfu::{get_serial_str, new_dfu_bootloader, DFUBootloaderRuntime}};
     use crate::storage::*;
     use crate::usbserial::*;
     use crate::shell;
-    use crate::ctlpins;
+    use crate::ctlpins::{self, CTLPinsTrait};
     use crate::powermeter::*;
     use crate::version;
     use crate::config::*;
+    use crate::edgelog::EdgeLog;
+    use crate::capture::{Capture, CAPTURE_LEN};
+    use crate::pwm::{self, Pwm};
+    use crate::sequence;
 
     type LedCmdType = gpio::PC15<Output<PushPull>>;
     type StorageSwitchType = StorageSwitch<gpio::PA15<Output<PushPull>>, gpio::PB3<Output<PushPull>>,
@@ -55,6 +65,15 @@ mod app {
     type DMATransfer = Transfer<Stream0<DMA2>, 0, Adc<ADC1>, PeripheralToMemory, &'static mut [u16; 2]>;
 
     const DUT_BUF_SIZE: usize = 1024;
+
+    // monotonic timer used to schedule software tasks, with a 100us resolution
+    // for the waits in power sequences; its SysTick interrupt runs every tick
+    // above all the tasks, so the timer interrupts below are delayed by up to
+    // the length of its handler, a few microseconds
+    const MONO_HZ: u32 = 10_000;
+    #[monotonic(binds = SysTick, default = true)]
+    type Mono = Systick<MONO_HZ>;
+
     // Resources shared between tasks
     #[shared]
     struct Shared {
@@ -78,6 +97,14 @@ mod app {
         power_meter: MAVPowerMeter,
 
         config: ConfigArea,
+
+        sequence_handle: Option<sequence_task::SpawnHandle>, // next scheduled step of the running sequence
+
+        to_dut_serial: Producer<'static, u8, DUT_BUF_SIZE>, // queue of characters to send to the DUT, from the shell and sequences
+
+        edge_log: EdgeLog, // edges captured on the CTL and power enable lines
+
+        capture: Capture, // samples of the CTL and power enable lines taken by TIM5
     }
 
     // Local resources to specific tasks (cannot be shared)
@@ -86,7 +113,6 @@ mod app {
         _button: gpio::PA0<Input>,
         usart_rx: Rx<pac::USART1>,
         usart_tx: Tx<pac::USART1>,
-        to_dut_serial: Producer<'static, u8, DUT_BUF_SIZE>,          // queue of characters to send to the DUT
         to_dut_serial_consumer: Consumer<'static, u8, DUT_BUF_SIZE>, // consumer side of the queue
         to_host_serial: Producer<'static, u8, DUT_BUF_SIZE>,          // queue of characters to send to the DUT
         to_host_serial_consumer: Consumer<'static, u8, DUT_BUF_SIZE>, // consumer side of the queue
@@ -122,13 +148,20 @@ mod app {
 
         let _button = gpioa.pa0.into_pull_up_input();
 
-        let ctl_pins = ctlpins::CTLPins::new(gpioa.pa5.into_dynamic(),          // ctl_a
+        // the config holds the pin states to apply at boot
+        let config = ConfigArea::new(stm32f4xx_hal::flash::LockedFlash::new(dp.FLASH));
+
+        let mut ctl_pins = ctlpins::CTLPins::new(gpioa.pa5.into_dynamic(),          // ctl_a
                                              gpioa.pa6.into_dynamic(),          // ctl_b
                                              gpioa.pa7.into_dynamic(),          // ctl_c
                                              gpioa.pa8.into_dynamic(),          // ctl_d
                                              gpioa.pa9.into_dynamic(),          // reset
-                                             gpioa.pa4.into_push_pull_output()  // power enable
+                                             gpioa.pa4.into_push_pull_output(), // power enable
+                                             Pwm::new(dp.TIM1, dp.TIM3, dp.TIM4, &clocks), // PWM on a ctl pin
+                                             clocks.sysclk().raw(),             // times the I2C clock
+                                             config.get().boot_states()
                                             );
+        ctl_pins.set_aliases(config.get().aliases);
 
         let pins = (gpiob.pb6, gpiob.pb7);
         let usart = Serial::new(
@@ -183,6 +216,10 @@ mod app {
         timer.start(10.millis()).unwrap(); //100Hz
         timer.listen(timer::Event::Update);
 
+        // TIM5 sets the sampling rate of the capture command
+        let capture_samples = cortex_m::singleton!(: [u8; CAPTURE_LEN] = [0; CAPTURE_LEN]).unwrap();
+        let capture = Capture::new(dp.TIM5.counter_hz(&clocks), capture_samples);
+
         // Pull the D+ pin down to send a RESET condition to the USB bus.
         let mut usb_dp = gpioa.pa12.into_push_pull_output();
         usb_dp.set_low();
@@ -231,7 +268,8 @@ mod app {
         let (to_dut_serial, to_dut_serial_consumer) = ctx.local.q_to_dut.split();
         let (to_host_serial, to_host_serial_consumer) = ctx.local.q_from_dut.split();
 
-        let config = ConfigArea::new(stm32f4xx_hal::flash::LockedFlash::new(dp.FLASH));
+
+        let mono = Systick::new(ctx.core.SYST, clocks.sysclk().to_Hz());
 
         (
             Shared {
@@ -249,12 +287,15 @@ mod app {
                 ctl_pins,
                 power_meter,
                 config,
+                sequence_handle: None,
+                to_dut_serial,
+                edge_log: EdgeLog::new(),
+                capture,
             },
             Local {
                 _button,
                 usart_tx,
                 usart_rx,
-                to_dut_serial,
                 to_dut_serial_consumer,
                 to_host_serial,
                 to_host_serial_consumer,
@@ -262,22 +303,25 @@ mod app {
             },
             // Move the monotonic timer to the RTIC run-time, this enables
             // scheduling
-            init::Monotonics(),
+            init::Monotonics(mono),
         )
     }
 
-    #[task(binds = USART1, priority=1, local = [usart_rx, to_host_serial], shared = [shell_status, led_rx])]
+    #[task(binds = USART1, priority=1, local = [usart_rx, to_host_serial], shared = [shell_status, led_rx, ctl_pins])]
     fn usart_task(cx: usart_task::Context){
         let usart_rx = cx.local.usart_rx;
         let shell_status = cx.shared.shell_status;
         let led_rx = cx.shared.led_rx;
+        let ctl_pins = cx.shared.ctl_pins;
         let to_host_serial = cx.local.to_host_serial;
 
-        (shell_status, led_rx).lock(|shell_status, led_rx| {
+        (shell_status, led_rx, ctl_pins).lock(|shell_status, led_rx, ctl_pins| {
             while usart_rx.is_rx_not_empty() {
                 led_rx.set_low();
                 match usart_rx.read() {
                     Ok(b) => {
+                        // a running sequence could be waiting for a serial pattern
+                        ctl_pins.feed_serial(b);
                         if shell_status.console_mode || shell_status.monitor_enabled {
                             to_host_serial.enqueue(b).ok(); // this could over-run but it's ok the only solution would be a bigger buffer
                         }
@@ -343,7 +387,7 @@ mod app {
         }
     }
 
-    #[task(binds = OTG_FS, shared = [usb_dev, shell, shell_status, dfu, ctl, led_cmd, storage, ctl_pins, power_meter, config], local=[esc_cnt:u8 = 0, to_dut_serial])]
+    #[task(binds = OTG_FS, shared = [usb_dev, shell, shell_status, dfu, ctl, led_cmd, storage, ctl_pins, power_meter, config, sequence_handle, to_dut_serial, edge_log, capture], local=[esc_cnt:u8 = 0])]
     fn usb_task(mut cx: usb_task::Context) {
         let usb_dev         = &mut cx.shared.usb_dev;
         let shell           = &mut cx.shared.shell;
@@ -352,29 +396,33 @@ mod app {
         let ctl             = &mut cx.shared.ctl;
         let led_cmd         = &mut cx.shared.led_cmd;
         let storage         = &mut cx.shared.storage;
-        let to_dut_serial   = cx.local.to_dut_serial;
+        let to_dut_serial   = &mut cx.shared.to_dut_serial;
+        let edge_log        = &mut cx.shared.edge_log;
+        let capture         = &mut cx.shared.capture;
 
         let esc_cnt         = cx.local.esc_cnt;
         let ctl_pins        = &mut cx.shared.ctl_pins;
         let power_meter     = &mut cx.shared.power_meter;
         let config          = &mut cx.shared.config;
 
-        (usb_dev, dfu, ctl, shell, shell_status, led_cmd, storage, ctl_pins, power_meter, config).lock(
+        let sequence_started = (usb_dev, dfu, ctl, shell, shell_status, led_cmd, storage, ctl_pins, power_meter, config).lock(
             |usb_dev, dfu, ctl, shell, shell_status, led_cmd, storage, ctl_pins, power_meter, config| {
             let serial1 = shell.get_serial_mut();
 
             if !usb_dev.poll(&mut [serial1, dfu, ctl]) {
-                return;
+                return false;
             }
 
-            ctl.post_poll(config, ctl_pins, storage, power_meter);
+            ctl.post_poll(config, ctl_pins, storage, power_meter, &mut *edge_log, &mut *capture);
 
-            let available_to_dut = to_dut_serial.capacity()-to_dut_serial.len();
+            let available_to_dut = to_dut_serial.lock(|to_dut_serial| to_dut_serial.capacity()-to_dut_serial.len());
 
             let mut send_to_dut = |buf: &[u8]|{
-                for b in buf {
-                    to_dut_serial.enqueue(*b).ok();
-                }
+                to_dut_serial.lock(|to_dut_serial| {
+                    for b in buf {
+                        to_dut_serial.enqueue(*b).ok();
+                    }
+                });
                 return
             };
 
@@ -404,9 +452,91 @@ mod app {
                     }
                 }
             } else {
-                shell::handle_shell_commands(shell, shell_status, led_cmd, storage, ctl_pins, &mut send_to_dut, power_meter, config);
+                shell::handle_shell_commands(shell, shell_status, led_cmd, storage, ctl_pins, &mut send_to_dut,
+                                             power_meter, config, &mut *edge_log, &mut *capture);
             }
+            ctl_pins.take_started()
         });
+
+        // power sequences started by the shell or the control interface run in sequence_task
+        if sequence_started {
+            cx.shared.sequence_handle.lock(|handle| schedule_sequence(handle, 0));
+        }
+    }
+
+    #[task(shared=[ctl_pins, power_meter, storage, shell, shell_status, to_dut_serial, sequence_handle])]
+    fn sequence_task(mut cx: sequence_task::Context) {
+        let now = monotonics::now().duration_since_epoch().to_millis();
+        let ctl_pins = &mut cx.shared.ctl_pins;
+        let power_meter = &mut cx.shared.power_meter;
+        let storage = &mut cx.shared.storage;
+        let shell = &mut cx.shared.shell;
+        let shell_status = &mut cx.shared.shell_status;
+        let to_dut_serial = &mut cx.shared.to_dut_serial;
+        let next = (ctl_pins, power_meter, storage, shell, shell_status, to_dut_serial).lock(
+            |ctl_pins, power_meter, storage, shell, shell_status, to_dut_serial| {
+            // the text of send steps is already unescaped, but outside console mode
+            // idle handles escapes in the queue, so backslashes must be escaped again
+            let escape = !shell_status.console_mode;
+            let mut send_to_dut = |buf: &[u8]| {
+                for b in buf {
+                    if escape && *b == b'\\' {
+                        to_dut_serial.enqueue(b'\\').ok();
+                    }
+                    to_dut_serial.enqueue(*b).ok();
+                }
+            };
+            let next = ctl_pins.poll_sequence(now, power_meter, storage, &mut send_to_dut);
+            // print the steps of a traced sequence as they are executed
+            ctl_pins.write_trace(shell);
+            next
+        });
+
+        cx.shared.sequence_handle.lock(|handle| {
+            match next {
+                Some(us) => schedule_sequence(handle, us),
+                None => *handle = None,
+            }
+        });
+    }
+
+    // schedule the next step of the running sequence, replacing any step still
+    // pending from a sequence that was overridden
+    fn schedule_sequence(handle: &mut Option<sequence_task::SpawnHandle>, us: u32) {
+        if let Some(h) = handle.take() {
+            h.cancel().ok();
+        }
+        // round up to the timer resolution so waits are never shorter than requested
+        let us_per_tick = (1_000_000 / MONO_HZ) as u64;
+        let ticks = (us as u64 + us_per_tick - 1) / us_per_tick;
+        *handle = sequence_task::spawn_after(TimerDurationU64::<MONO_HZ>::from_ticks(ticks)).ok();
+    }
+
+    // edges on the CTL lines (EXTI 5 to 9) and on the power enable line (EXTI 4)
+    // run at a higher priority so they are timestamped as soon as possible
+    #[task(binds = EXTI9_5, priority = 2, shared = [edge_log])]
+    fn ctl_edge(mut cx: ctl_edge::Context) {
+        let now = monotonics::now().duration_since_epoch().to_micros();
+        cx.shared.edge_log.lock(|edge_log| edge_log.capture(now));
+    }
+
+    #[task(binds = EXTI4, priority = 2, shared = [edge_log])]
+    fn power_edge(mut cx: power_edge::Context) {
+        let now = monotonics::now().duration_since_epoch().to_micros();
+        cx.shared.edge_log.lock(|edge_log| edge_log.capture(now));
+    }
+
+    // samples of the capture command, at the highest task priority so the rate
+    // is steady, apart from the jitter of a few microseconds added by SysTick
+    #[task(binds = TIM5, priority = 3, shared = [capture])]
+    fn capture_sample(mut cx: capture_sample::Context) {
+        cx.shared.capture.lock(|capture| capture.sample());
+    }
+
+    // edges of the software PWM on CTL_A, without resources so no lock delays them
+    #[task(binds = TIM4, priority = 3)]
+    fn pwm_edge(_cx: pwm_edge::Context) {
+        pwm::on_interrupt();
     }
 
     #[task(binds = TIM2, shared=[timer, dfu,  led_rx, led_tx, led_cmd, adc_dma_transfer])]
@@ -463,9 +593,12 @@ mod app {
         let R9 = 470.0; // R9 is the bottom resistor in the voltage divider
         let vin = vout_sense_V * (R8 + R9) / R9;
 
+        let now = monotonics::now().duration_since_epoch().to_micros();
         power_meter.lock(|power_meter| {
             power_meter.feed_voltage(vin);
             power_meter.feed_current(current_A);
+            // every sample is integrated into the energy, not the moving averages
+            power_meter.feed_power(vin * current_A, now);
         });
 
     }
@@ -474,7 +607,7 @@ mod app {
     // Background task, runs whenever no other tasks are running
     #[idle(local=[to_dut_serial_consumer, usart_tx], shared=[led_tx, shell_status])]
     fn idle(mut ctx: idle::Context) -> ! {
-        // the source of this queue is the send command from the shell
+        // the source of this queue is the send command from the shell and the send steps of sequences
         let to_dut_serial_consumer = &mut ctx.local.to_dut_serial_consumer;
         let shell_status = &mut ctx.shared.shell_status;
 
@@ -505,9 +638,13 @@ mod app {
 
                             if escaped == true {
                                 escaped = false;
-                                final_c = match escaped_char(c) {
+                                final_c = match sequence::escaped_char(c) {
                                     Some(c) => c,
-                                    None =>  continue,
+                                    None => {
+                                        // \w WAIT DELAY
+                                        cortex_m::asm::delay(50*1000*1000);
+                                        continue;
+                                    }
                                 }
                             }
                         }
@@ -533,22 +670,5 @@ mod app {
         }
     }
 
-    fn escaped_char(c:u8) -> Option<u8> {
-
-        match c {
-            0x5c => { Some(0x5c) }, // \\
-            0x6e => { Some(0x0a) }, // \n
-            0x72 => { Some(0x0d) }, // \r
-            0x74 => { Some(0x09) }, // \t
-            0x61 => { Some(0x07) }, // \a alert
-            0x62 => { Some(0x08) }, // \b backspace
-            0x65 => { Some(0x1b) }, // \e escape character
-            0x63 => { Some(0x03) }, // \c // CTRL+C
-            0x64 => { Some(0x04) }, // \d CTRL+D
-            0x77 => { cortex_m::asm::delay(50*1000*1000); None },// \w WAIT DELAY
-            _ => Some(c)
-        }
-    }
-
 
 }
@@ -7,21 +7,33 @@ pub trait PowerMeter {
         fn get_current(&mut self) -> f32;
         fn feed_voltage(&mut self, value:f32);
         fn feed_current(&mut self, value:f32);
+        fn feed_power(&mut self, value:f32, now_us: u64);
+        fn get_energy(&mut self) -> f64;
+        fn get_energy_time(&mut self) -> u64;
+        fn reset_energy(&mut self);
         fn write_trace(&mut self, writer: &mut dyn Write);
         fn write(&mut self, writer: &mut dyn Write);
 
 }
 
-// Moving average power meter
+pub const JOULES_PER_WH: f64 = 3600.0;
+
+// Moving average power meter, the energy is integrated from the unfiltered
+// power samples over the time between them, in f64 so the small increments
+// of every sample are not lost once the total grows
 pub struct MAVPowerMeter {
         voltage: filter::MovingAverage,
         current: filter::MovingAverage,
+        energy: f64,               // joules since the last reset
+        energy_time: u64,          // microseconds integrated since the last reset
+        last_sample: Option<u64>,  // time of the previous power sample, None after a reset
 }
 
 impl MAVPowerMeter {
         pub fn new() -> Self {
                 Self{voltage: filter::MovingAverage::new(),
-                     current: filter::MovingAverage::new()}
+                     current: filter::MovingAverage::new(),
+                     energy: 0.0, energy_time: 0, last_sample: None}
         }
 }
 
@@ -41,16 +53,40 @@ impl PowerMeter for MAVPowerMeter {
         fn feed_current(&mut self, value:f32) {
                 self.current.feed(value);
         }
+        fn feed_power(&mut self, value:f32, now_us: u64) {
+                // the first sample after a reset only starts the integration
+                if let Some(last) = self.last_sample {
+                        let us = now_us.saturating_sub(last);
+                        self.energy += value as f64 * us as f64 / 1_000_000.0;
+                        self.energy_time += us;
+                }
+                self.last_sample = Some(now_us);
+        }
+        fn get_energy(&mut self) -> f64 {
+                self.energy
+        }
+        fn get_energy_time(&mut self) -> u64 {
+                self.energy_time
+        }
+        fn reset_energy(&mut self) {
+                self.energy = 0.0;
+                self.energy_time = 0;
+                self.last_sample = None;
+        }
 
         fn write_trace(&mut self, writer: &mut dyn Write) {
             let pw_w = self.get_power();
-            write!(writer, "{:.2}W> ",pw_w).ok();
+            let en_wh = self.get_energy() / JOULES_PER_WH;
+            write!(writer, "{:.2}W {:.4}Wh> ",pw_w, en_wh).ok();
         }
         fn write(&mut self, writer: &mut dyn Write) {
             let pw_w = self.get_power();
             let pw_v = self.get_voltage();
             let pw_a = self.get_current();
+            let en_j = self.get_energy();
+            let en_s = self.get_energy_time() as f64 / 1_000_000.0;
 
-            write!(writer, "{:.2}A {:.2}V {:.2}W", pw_a, pw_v, pw_w).ok();
+            write!(writer, "{:.2}A {:.2}V {:.2}W {:.2}J {:.4}Wh in {:.1}s",
+                   pw_a, pw_v, pw_w, en_j, en_j / JOULES_PER_WH, en_s).ok();
         }
 }
\ No newline at end of file
@@ -0,0 +1,225 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use stm32f4xx_hal::{pac, rcc::Clocks, timer::Timer};
+
+use crate::ctlpins::gpio_bit;
+use crate::sequence::{Pin, MAX_PWM_FREQUENCY};
+
+// PWM on a CTL pin: CTL_B and CTL_C (PA6, PA7) are channels 1 and 2 of TIM3,
+// CTL_D and RESET (PA8, PA9) channels 1 and 2 of TIM1, which drive the pins
+// through their alternate function without any interrupt.
+//
+// CTL_A (PA5) is only on a channel of TIM2, the periodic tick, so it gets a
+// software PWM: TIM4 counts the period and its interrupts write the pin through
+// the GPIOA BSRR register, high on the update event and low on the compare
+// event of channel 1. The interrupt latency adds some jitter to its edges,
+// which is fine for clocks and wake pulses of up to MAX_PWM_FREQUENCY.
+
+// bit of the pin driven by the software PWM in the GPIOA registers, 0 when
+// stopped, shared with the TIM4 interrupt which uses no other resource
+static PWM_BIT: AtomicU32 = AtomicU32::new(0);
+
+const SR_UIF: u32 = 1 << 0;
+const SR_CC1IF: u32 = 1 << 1;
+
+// PWM mode 1 with the compare register preloaded, in the CCMR1 bits of channel 1,
+// the output is high while the counter is below the compare register
+const CCMR1_OC1_PWM1: u32 = 0b110 << 4 | 1 << 3;
+const BDTR_MOE: u32 = 1 << 15; // main output enable of the TIM1 channels
+
+// alternate functions of the timer channels on GPIOA
+const AF_TIM1: u32 = 1;
+const AF_TIM3: u32 = 2;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PwmError {
+    InvalidFrequency,
+    InvalidDuty,
+}
+
+impl fmt::Display for PwmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PwmError::InvalidFrequency => write!(f, "the frequency must be between 1Hz and {}Hz", MAX_PWM_FREQUENCY),
+            PwmError::InvalidDuty      => write!(f, "the duty cycle must be between 0 and 100%"),
+        }
+    }
+}
+
+pub fn validate(hz: u32, duty: u8) -> Result<(), PwmError> {
+    if hz == 0 || hz > MAX_PWM_FREQUENCY {
+        return Err(PwmError::InvalidFrequency);
+    }
+    if duty > 100 {
+        return Err(PwmError::InvalidDuty);
+    }
+    Ok(())
+}
+
+pub struct Pwm {
+    // owned so nothing else uses the timers, accessed through their registers
+    _tim1: pac::TIM1,
+    _tim3: pac::TIM3,
+    _tim4: pac::TIM4,
+    apb1_clock: u32,  // input clock of TIM3 and TIM4 in Hz
+    apb2_clock: u32,  // input clock of TIM1 in Hz
+    pin: Option<Pin>, // pin driven by the PWM, None when stopped
+}
+
+impl Pwm {
+    pub fn new(tim1: pac::TIM1, tim3: pac::TIM3, tim4: pac::TIM4, clocks: &Clocks) -> Self {
+        // the HAL timer enables and resets the peripherals
+        Pwm {
+            _tim1: Timer::new(tim1, clocks).release(),
+            _tim3: Timer::new(tim3, clocks).release(),
+            _tim4: Timer::new(tim4, clocks).release(),
+            apb1_clock: clocks.timclk1().raw(),
+            apb2_clock: clocks.timclk2().raw(),
+            pin: None,
+        }
+    }
+
+    /// Drives the pin, which must be a push-pull output, with a PWM starting
+    /// with the high part of the period.
+    pub fn start(&mut self, pin: Pin, hz: u32, duty: u8) -> Result<(), PwmError> {
+        validate(hz, duty)?;
+        self.stop();
+        // recorded first, so stop releases the pin whatever the duty cycle
+        self.pin = Some(pin);
+        let clock = match pin {
+            Pin::D | Pin::Reset => self.apb2_clock,
+            _ => self.apb1_clock,
+        };
+        // the prescaler keeps the period within the 16 bits of the counter
+        let ticks = clock / hz;
+        let psc = ticks / 0x1_0000 + 1;
+        let arr = ticks / psc - 1;
+        // a compare value past arr keeps the channels high for a 100% duty cycle
+        let ccr = (arr + 1) * duty as u32 / 100;
+        let bit = gpio_bit(pin);
+        unsafe {
+            match pin {
+                Pin::A => {
+                    if duty == 0 || duty == 100 {
+                        // no edges, just a level
+                        let bsrr = if duty == 0 { bit << 16 } else { bit };
+                        (*pac::GPIOA::ptr()).bsrr.write(|w| w.bits(bsrr));
+                        return Ok(());
+                    }
+                    PWM_BIT.store(bit, Ordering::Relaxed);
+                    let tim = &*pac::TIM4::ptr();
+                    tim.psc.write(|w| w.bits(psc - 1));
+                    tim.arr.write(|w| w.bits(arr));
+                    tim.ccr1.write(|w| w.bits(ccr));
+                    tim.egr.write(|w| w.bits(1)); // UG, loads the prescaler
+                    tim.sr.write(|w| w.bits(0));
+                    tim.dier.write(|w| w.bits(SR_UIF | SR_CC1IF)); // same bits as the interrupt enables
+                    (*pac::GPIOA::ptr()).bsrr.write(|w| w.bits(bit));
+                    tim.cr1.write(|w| w.bits(1)); // CEN
+                }
+                Pin::B | Pin::C => {
+                    // channel 1 on PA6, channel 2 on PA7
+                    let shift = if pin == Pin::B { 0 } else { 8 };
+                    let tim = &*pac::TIM3::ptr();
+                    tim.psc.write(|w| w.bits(psc - 1));
+                    tim.arr.write(|w| w.bits(arr));
+                    if pin == Pin::B {
+                        tim.ccr1.write(|w| w.bits(ccr));
+                    } else {
+                        tim.ccr2.write(|w| w.bits(ccr));
+                    }
+                    tim.ccmr1_output().write(|w| w.bits(CCMR1_OC1_PWM1 << shift));
+                    tim.ccer.write(|w| w.bits(1 << (shift / 2))); // CC1E or CC2E
+                    tim.egr.write(|w| w.bits(1)); // UG, loads the prescaler and compare value
+                    tim.cr1.write(|w| w.bits(1)); // CEN
+                    set_alternate(pin, AF_TIM3);
+                }
+                Pin::D | Pin::Reset => {
+                    // channel 1 on PA8, channel 2 on PA9
+                    let shift = if pin == Pin::D { 0 } else { 8 };
+                    let tim = &*pac::TIM1::ptr();
+                    tim.psc.write(|w| w.bits(psc - 1));
+                    tim.arr.write(|w| w.bits(arr));
+                    if pin == Pin::D {
+                        tim.ccr1.write(|w| w.bits(ccr));
+                    } else {
+                        tim.ccr2.write(|w| w.bits(ccr));
+                    }
+                    tim.ccmr1_output().write(|w| w.bits(CCMR1_OC1_PWM1 << shift));
+                    tim.ccer.write(|w| w.bits(1 << (shift / 2))); // CC1E or CC2E
+                    tim.bdtr.write(|w| w.bits(BDTR_MOE));
+                    tim.egr.write(|w| w.bits(1)); // UG, loads the prescaler and compare value
+                    tim.cr1.write(|w| w.bits(1)); // CEN
+                    set_alternate(pin, AF_TIM1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops the PWM, leaving its pin a push-pull output driven low.
+    pub fn stop(&mut self) {
+        let pin = match self.pin.take() {
+            Some(pin) => pin,
+            None => return,
+        };
+        let bit = gpio_bit(pin);
+        unsafe {
+            match pin {
+                Pin::A => {
+                    let tim = &*pac::TIM4::ptr();
+                    tim.cr1.write(|w| w.bits(0));
+                    tim.dier.write(|w| w.bits(0));
+                    PWM_BIT.store(0, Ordering::Relaxed);
+                }
+                Pin::B | Pin::C => {
+                    let tim = &*pac::TIM3::ptr();
+                    tim.cr1.write(|w| w.bits(0));
+                    tim.ccer.write(|w| w.bits(0));
+                }
+                Pin::D | Pin::Reset => {
+                    let tim = &*pac::TIM1::ptr();
+                    tim.cr1.write(|w| w.bits(0));
+                    tim.ccer.write(|w| w.bits(0));
+                    tim.bdtr.write(|w| w.bits(0));
+                }
+            }
+            // the output register is low before the pin leaves the timer channel
+            let gpioa = &*pac::GPIOA::ptr();
+            gpioa.bsrr.write(|w| w.bits(bit << 16));
+            let n = bit.trailing_zeros();
+            gpioa.moder.modify(|r, w| w.bits(r.bits() & !(0b11 << (2 * n)) | 0b01 << (2 * n)));
+        }
+    }
+}
+
+// switches a pin from GPIO output to the timer channel of alternate function af
+unsafe fn set_alternate(pin: Pin, af: u32) {
+    let gpioa = &*pac::GPIOA::ptr();
+    let n = gpio_bit(pin).trailing_zeros();
+    if n < 8 {
+        gpioa.afrl.modify(|r, w| w.bits(r.bits() & !(0xf << (4 * n)) | af << (4 * n)));
+    } else {
+        gpioa.afrh.modify(|r, w| w.bits(r.bits() & !(0xf << (4 * (n - 8))) | af << (4 * (n - 8))));
+    }
+    gpioa.moder.modify(|r, w| w.bits(r.bits() & !(0b11 << (2 * n)) | 0b10 << (2 * n)));
+}
+
+/// Writes the edges of the PWM, called from the TIM4 interrupt.
+pub fn on_interrupt() {
+    let bit = PWM_BIT.load(Ordering::Relaxed);
+    unsafe {
+        let tim = &*pac::TIM4::ptr();
+        let sr = tim.sr.read().bits();
+        // the flags are cleared by writing 0
+        tim.sr.write(|w| w.bits(!sr));
+        let gpioa = &*pac::GPIOA::ptr();
+        if sr & SR_UIF != 0 {
+            gpioa.bsrr.write(|w| w.bits(bit));
+        }
+        if sr & SR_CC1IF != 0 {
+            gpioa.bsrr.write(|w| w.bits(bit << 16));
+        }
+    }
+}
@@ -0,0 +1,1159 @@
+use core::fmt;
+
+use heapless::Vec;
+
+// Power sequences (power_on, power_off, power_rescue) are stored in the config
+// as ascii strings, and parsed into a list of steps before being executed by
+// CTLPins. The format is:
+//
+// ord[,ord]*
+// where ord is:
+//   - a,b,c,d,r followed by a state: h,l,z or o (open-drain low), u (input
+//     with pull-up), d (input with pull-down)
+//   - a,b,c,d,r followed by ~, a frequency in Hz, : and a duty cycle in %,
+//     drives a PWM on the pin, i.e. a~10k:50 for a 10kHz clock, only one pin
+//     can have a PWM at a time, and setting the pin to a state stops it
+//   - a,b,c,d,r followed by ? and a level: h,l, waits until the pin input reads
+//     that level, i.e. c?h to wait for a power good signal on CTL_C
+//   - @ and a pin alias from the config can be used instead of a,b,c,d,r, with
+//     a : before the state, i.e. @REC:l, @PGOOD?h or @CLK~1k:50
+//   - w followed by a duration to wait
+//   - p followed by 0 or 1, which is the desired power state
+//   - e followed by a quoted string, waits until the string is received from
+//     the DUT serial port, the string accepts the same escapes as the send
+//     command but \w, plus \" for a quote, i.e. e"Hit any key"
+//   - s followed by a quoted string, sends the string to the DUT serial port,
+//     with the same escapes as e, i.e. s"\c" to interrupt u-boot autoboot
+//   - i or v followed by > or < and a decimal number, waits until the current
+//     (in A) or the voltage (in V) measured by the power meter is above or
+//     below the number, i.e. i<0.05. The power meter averages the last 2s.
+//   - m followed by d, h or o, connects the USB storage to the DUT, to the
+//     host, or disconnects it (off)
+//   - [ followed by orders and ] x and a natural number, repeats the orders
+//     in the brackets the given number of times (1 to 65535), i.e.
+//     [bL,w1,bZ,w1]x3, repeats can be nested up to 4 levels, and the product
+//     of the counts of nested repeats can't be above 1000000
+//   - ( followed by pin states and ), sets the pins together, the output
+//     levels change with a single GPIO write, i.e. (aL,bL,rH) for DUTs
+//     sampling strap pins on a single edge. Each pin can only appear once.
+//  , is ignored and used as a visual separator of orders
+//
+// Durations are a natural number followed by a unit: us, ms or s, a number
+// without unit is a number of 100ms, i.e. w5 and w500ms are the same. Waits are
+// timed by the SysTick timer with a resolution of 100us.
+//
+// Steps which wait for a condition (e, i, v, ?) can be followed by t and a
+// duration, the timeout, the default timeout is 10s. If the timeout expires
+// the sequence is aborted. A send step after a duration needs a , in between,
+// as in t5s"boot" the s could be the unit or the send step.
+//
+// opcodes and states are case insensitive, and a NUL character terminates
+// the sequence (the config storage is zero padded).
+//
+// i.e. assuming A=REC , B=POWER_BTN for a jetson board, we could have:
+//
+//   enter flashing mode:
+//   "p1,aL,rL,w1,rZ,w1"  => Power on, REC low, reset LOW, wait 100ms, reset HiZ, wait 100ms
+//
+//   power off via signal:
+//   "p1,bL,w110,bZ" => Power on, POWER_BTN low, wait 11s, POWER_BTN HiZ
+//
+//   power on via signal:
+//   "p1,bL,w5,bZ" => Power on, POWER_BTN low, wait 500ms, POWER_BTN HiZ
+//
+//   enter u-boot:
+//   "aL,p1,e"Hit any key"t50,aZ" => REC low, Power on, wait up to 5s for the
+//                                    u-boot prompt, REC HiZ
+//
+//   stop in the u-boot prompt:
+//   "p1,e"Hit any key"t5s,s"\c"" => Power on, wait up to 5s for the autoboot
+//                                     message, send CTRL+C to interrupt it
+//
+//   boot from the image written to the USB storage:
+//   "p0,md,w5,p1,e"login:"t60s" => Power off, storage to the DUT, wait 500ms,
+//                                  Power on, wait up to 60s for the login prompt
+//
+//   graceful power off:
+//   "bL,w500ms,bZ,i<0.05t30s,p0" => press POWER_BTN for 500ms, wait up to 30s until
+//                                   the DUT draws less than 50mA, power off
+
+pub const MAX_STEPS: usize = 96;
+pub const MAX_TEXT: usize = 128;
+pub const DEFAULT_TIMEOUT_US: u32 = 10_000_000;
+pub const MAX_NESTING: usize = 4;
+pub const MAX_ITERATIONS: u32 = 1_000_000;
+pub const MAX_PWM_FREQUENCY: u32 = 20_000;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Pin {
+    A,
+    B,
+    C,
+    D,
+    Reset,
+}
+
+// create an enum with the possible states of a CTL pin: push-pull High and
+// Low, Floating input, open-drain Low and inputs with a weak pull-up/down
+// this is used to set the CTL pins to a specific state
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PinState {
+    High,
+    Low,
+    Floating,
+    OpenDrainLow, // sinks current like Low, for lines with their own pull-up
+    PullUp,
+    PullDown,
+}
+
+pub const PINS: [Pin; 5] = [Pin::Reset, Pin::A, Pin::B, Pin::C, Pin::D];
+
+pub const ALIAS_LEN: usize = 16;
+
+/// Returns the position of a pin in PINS, the order of the per pin arrays.
+pub fn pin_index(pin: Pin) -> usize {
+    PINS.iter().position(|p| *p == pin).unwrap()
+}
+
+// user given names of the pins in PINS order, zero padded, i.e. REC or POWER_BTN
+pub type Aliases = [[u8; ALIAS_LEN]; 5];
+pub const NO_ALIASES: Aliases = [[0; ALIAS_LEN]; 5];
+
+// states to set on several pins at once in PINS order, None leaves a pin as is
+pub type PinStates = [Option<PinState>; 5];
+
+/// Returns the letter used for a pin state in sequences, i.e. l for Low.
+pub fn pin_state_letter(state: PinState) -> u8 {
+    match state {
+        PinState::High         => b'h',
+        PinState::Low          => b'l',
+        PinState::Floating     => b'z',
+        PinState::OpenDrainLow => b'o',
+        PinState::PullUp       => b'u',
+        PinState::PullDown     => b'd',
+    }
+}
+
+/// Returns the pin state for a lowercase letter used in sequences.
+pub fn pin_state_from_letter(letter: u8) -> Option<PinState> {
+    match letter {
+        b'h' => Some(PinState::High),
+        b'l' => Some(PinState::Low),
+        b'z' => Some(PinState::Floating),
+        b'o' => Some(PinState::OpenDrainLow),
+        b'u' => Some(PinState::PullUp),
+        b'd' => Some(PinState::PullDown),
+        _    => None,
+    }
+}
+
+/// Returns the pin with the given alias, aliases are case insensitive.
+pub fn alias_pin(aliases: &Aliases, name: &[u8]) -> Option<Pin> {
+    if name.is_empty() {
+        return None;
+    }
+    aliases.iter().zip(PINS).find_map(|(alias, pin)| {
+        let len = alias.iter().position(|c| *c == 0).unwrap_or(ALIAS_LEN);
+        if alias[..len].eq_ignore_ascii_case(name) { Some(pin) } else { None }
+    })
+}
+
+// a string stored in the text area of a Program
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Text {
+    start: u16,
+    len: u16,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Quantity {
+    Current,
+    Voltage,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum StorageTarget {
+    Dut,
+    Host,
+    Off,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Step {
+    Set(Pin, PinState),
+    SetPins(PinStates),
+    Pwm(Pin, u32, u8), // frequency in Hz, duty cycle in %
+    Level(Pin, bool, u32), // input level to wait for, timeout in microseconds
+    Wait(u32), // microseconds
+    Power(bool),
+    Expect(Text, u32), // serial pattern, timeout in microseconds
+    Send(Text),        // text for the DUT serial port
+    Storage(StorageTarget),
+    Above(Quantity, f32, u32), // threshold, timeout in microseconds
+    Below(Quantity, f32, u32),
+    Repeat(u16),         // start of a repeat block, index of its EndRepeat step
+    EndRepeat(u16, u16), // index of the Repeat step, number of iterations
+}
+
+pub struct Program {
+    pub steps: Vec<Step, MAX_STEPS>,
+    text: Vec<u8, MAX_TEXT>,
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Program { steps: Vec::new(), text: Vec::new() }
+    }
+
+    pub fn text(&self, text: Text) -> &[u8] {
+        &self.text[text.start as usize..(text.start + text.len) as usize]
+    }
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ParseErrorKind {
+    UnknownOpcode(u8),
+    UnknownPinState(u8),
+    UnknownLevel(u8),
+    UnknownUnit(u8),
+    UnknownAlias,
+    ExpectedAliasState,
+    UnknownPowerState(u8),
+    UnknownStorageTarget(u8),
+    MissingNumber,
+    NumberTooLarge,
+    UnexpectedEnd,
+    TooManySteps,
+    ExpectedQuote,
+    EmptyText,
+    TextTooLong,
+    WaitEscape,
+    AmbiguousSend,
+    ExpectedComparison(u8),
+    UnmatchedBracket,
+    UnclosedBracket,
+    NestingTooDeep,
+    ExpectedRepeat,
+    InvalidRepeatCount,
+    TooManyIterations,
+    UnmatchedParen,
+    UnclosedParen,
+    ExpectedPinSet(u8),
+    DuplicatePin,
+    ExpectedDuty,
+    InvalidPwm,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ParseError {
+    pub column: usize, // 1-based column of the offending character
+    pub kind: ParseErrorKind,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {}: ", self.column)?;
+        match self.kind {
+            ParseErrorKind::UnknownOpcode(c)     => write!(f, "unknown opcode '{}'", c as char),
+            ParseErrorKind::UnknownPinState(c)   => write!(f, "unknown pin state '{}', expected h, l, z, o, u or d", c as char),
+            ParseErrorKind::UnknownLevel(c)      => write!(f, "unknown level '{}', expected h or l", c as char),
+            ParseErrorKind::UnknownUnit(c)       => write!(f, "unknown unit '{}', expected us, ms or s", c as char),
+            ParseErrorKind::UnknownAlias         => write!(f, "unknown pin alias"),
+            ParseErrorKind::ExpectedAliasState   => write!(f, "expected : and a state, or ? and a level after the alias"),
+            ParseErrorKind::UnknownPowerState(c) => write!(f, "unknown power state '{}', expected 0 or 1", c as char),
+            ParseErrorKind::UnknownStorageTarget(c) => write!(f, "unknown storage target '{}', expected d, h or o", c as char),
+            ParseErrorKind::MissingNumber        => write!(f, "expected a number"),
+            ParseErrorKind::NumberTooLarge       => write!(f, "number too large"),
+            ParseErrorKind::UnexpectedEnd        => write!(f, "unexpected end of sequence"),
+            ParseErrorKind::TooManySteps         => write!(f, "too many steps, max {}", MAX_STEPS),
+            ParseErrorKind::ExpectedQuote        => write!(f, "expected a quoted string"),
+            ParseErrorKind::EmptyText            => write!(f, "empty string"),
+            ParseErrorKind::TextTooLong          => write!(f, "strings too long, max {} bytes in total", MAX_TEXT),
+            ParseErrorKind::WaitEscape           => write!(f, "\\w is not supported in strings, use a w step"),
+            ParseErrorKind::AmbiguousSend        => write!(f, "s\" after a duration, use a , before a send step"),
+            ParseErrorKind::ExpectedComparison(c) => write!(f, "unknown comparison '{}', expected > or <", c as char),
+            ParseErrorKind::UnmatchedBracket     => write!(f, "] without a matching ["),
+            ParseErrorKind::UnclosedBracket      => write!(f, "[ without a matching ]"),
+            ParseErrorKind::NestingTooDeep       => write!(f, "too many nested repeats, max {}", MAX_NESTING),
+            ParseErrorKind::ExpectedRepeat       => write!(f, "expected x and a repeat count after ]"),
+            ParseErrorKind::InvalidRepeatCount   => write!(f, "repeat count must be between 1 and 65535"),
+            ParseErrorKind::TooManyIterations    => write!(f, "too many iterations of nested repeats, max {} in total", MAX_ITERATIONS),
+            ParseErrorKind::UnmatchedParen       => write!(f, ") without a matching ("),
+            ParseErrorKind::UnclosedParen        => write!(f, "( without a matching )"),
+            ParseErrorKind::ExpectedPinSet(c)    => write!(f, "unexpected '{}', only pin states can be set in ( )", c as char),
+            ParseErrorKind::DuplicatePin         => write!(f, "pin set twice in ( )"),
+            ParseErrorKind::ExpectedDuty         => write!(f, "expected : and a duty cycle after the frequency"),
+            ParseErrorKind::InvalidPwm           => write!(f, "PWM frequency must be 1 to {}Hz and duty 0 to 100%", MAX_PWM_FREQUENCY),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SequenceError {
+    Invalid(ParseError),
+    Busy, // another sequence is already in progress
+}
+
+impl From<ParseError> for SequenceError {
+    fn from(e: ParseError) -> Self {
+        SequenceError::Invalid(e)
+    }
+}
+
+impl fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SequenceError::Invalid(e) => write!(f, "invalid sequence, {}", e),
+            SequenceError::Busy       => write!(f, "another sequence is in progress"),
+        }
+    }
+}
+
+/// Parses a power sequence into a list of steps, the sequence ends at the end
+/// of the slice or at the first NUL character. Pins can be referred to by the
+/// given aliases.
+pub fn parse(sequence: &[u8], aliases: &Aliases) -> Result<Program, ParseError> {
+    let mut parser = Parser { sequence, pos: 0 };
+    let mut program = Program::new();
+    // Repeat steps waiting for their ], with the position of the [ for errors
+    // and the most iterations of the repeats nested in them
+    let mut open: Vec<(u16, usize, u32), MAX_NESTING> = Vec::new();
+
+    while let Some(ch) = parser.next() {
+        let step = match ch {
+            b',' => continue,
+            b'[' => {
+                let index = program.steps.len() as u16;
+                if open.push((index, parser.pos, 1)).is_err() {
+                    return Err(parser.error(ParseErrorKind::NestingTooDeep));
+                }
+                Step::Repeat(0) // patched once the ] is found
+            }
+            b']' => {
+                let (start, _, nested) = open.pop().ok_or(parser.error(ParseErrorKind::UnmatchedBracket))?;
+                let count = parser.repeat_count()?;
+                // a nested repeat runs all its iterations on every one of the outer repeat
+                let iterations = nested * count as u32;
+                if iterations > MAX_ITERATIONS {
+                    return Err(parser.error(ParseErrorKind::TooManyIterations));
+                }
+                if let Some((_, _, outer)) = open.last_mut() {
+                    *outer = (*outer).max(iterations);
+                }
+                let end = program.steps.len() as u16;
+                program.steps[start as usize] = Step::Repeat(end);
+                Step::EndRepeat(start, count)
+            }
+            b'a' => parser.pin_step(Pin::A)?,
+            b'b' => parser.pin_step(Pin::B)?,
+            b'c' => parser.pin_step(Pin::C)?,
+            b'd' => parser.pin_step(Pin::D)?,
+            b'r' => parser.pin_step(Pin::Reset)?,
+            b'(' => Step::SetPins(parser.pin_group(aliases)?),
+            b')' => return Err(parser.error(ParseErrorKind::UnmatchedParen)),
+            b'@' => {
+                let pin = parser.alias(aliases)?;
+                match parser.peek() {
+                    Some(b':') => parser.pos += 1,
+                    Some(b'?') | Some(b'~') => {}
+                    _ => return Err(ParseError { column: parser.pos + 1, kind: ParseErrorKind::ExpectedAliasState }),
+                }
+                parser.pin_step(pin)?
+            }
+            b'w' => Step::Wait(parser.duration()?),
+            b'p' => Step::Power(parser.power_state()?),
+            b'e' => {
+                let text = parser.text(&mut program)?;
+                Step::Expect(text, parser.timeout()?)
+            }
+            b's' => Step::Send(parser.text(&mut program)?),
+            b'm' => Step::Storage(parser.storage_target()?),
+            b'i' => parser.threshold(Quantity::Current)?,
+            b'v' => parser.threshold(Quantity::Voltage)?,
+            _ => return Err(parser.error(ParseErrorKind::UnknownOpcode(ch))),
+        };
+        if program.steps.push(step).is_err() {
+            return Err(parser.error(ParseErrorKind::TooManySteps));
+        }
+    }
+    if let Some((_, column, _)) = open.pop() {
+        return Err(ParseError { column, kind: ParseErrorKind::UnclosedBracket });
+    }
+    Ok(program)
+}
+
+/// Parses a duration with the same format as the sequence waits, i.e. 500ms,
+/// returned in microseconds.
+pub fn parse_duration(duration: &[u8]) -> Result<u32, ParseError> {
+    let mut parser = Parser { sequence: duration, pos: 0 };
+    let us = parser.duration()?;
+    match parser.next() {
+        None => Ok(us),
+        Some(ch) => Err(parser.error(ParseErrorKind::UnknownUnit(ch))),
+    }
+}
+
+/// Returns true if the sequence has no content, which for power_on/power_off
+/// means that the power pin is switched directly.
+pub fn is_empty(sequence: &[u8]) -> bool {
+    sequence.is_empty() || sequence[0] == b'\0'
+}
+
+/// Writes a step back in the sequence format, used to trace the execution
+/// of a sequence.
+pub fn write_step(w: &mut dyn fmt::Write, program: &Program, step: Step) -> fmt::Result {
+    match step {
+        Step::Set(pin, state) => write!(w, "{}{}", pin_char(pin), pin_state_letter(state) as char),
+        Step::Pwm(pin, hz, duty) => write!(w, "{}~{}:{}", pin_char(pin), hz, duty),
+        Step::SetPins(states) => {
+            w.write_char('(')?;
+            let mut sep = "";
+            for (pin, state) in PINS.iter().zip(states) {
+                if let Some(state) = state {
+                    write!(w, "{}{}{}", sep, pin_char(*pin), pin_state_letter(state) as char)?;
+                    sep = ",";
+                }
+            }
+            w.write_char(')')
+        }
+        Step::Level(pin, high, timeout) => {
+            write!(w, "{}?{}t", pin_char(pin), if high { 'h' } else { 'l' })?;
+            write_duration(w, timeout)
+        }
+        Step::Wait(us) => {
+            w.write_char('w')?;
+            write_duration(w, us)
+        }
+        Step::Power(on) => write!(w, "p{}", on as u8),
+        Step::Expect(text, timeout) => {
+            w.write_char('e')?;
+            write_text(w, program.text(text))?;
+            w.write_char('t')?;
+            write_duration(w, timeout)
+        }
+        Step::Send(text) => {
+            w.write_char('s')?;
+            write_text(w, program.text(text))
+        }
+        Step::Above(quantity, value, timeout) | Step::Below(quantity, value, timeout) => {
+            let quantity = match quantity {
+                Quantity::Current => 'i',
+                Quantity::Voltage => 'v',
+            };
+            let comparison = if let Step::Above(..) = step { '>' } else { '<' };
+            write!(w, "{}{}{:.3}t", quantity, comparison, value)?;
+            write_duration(w, timeout)
+        }
+        Step::Storage(target) => {
+            let target = match target {
+                StorageTarget::Dut  => 'd',
+                StorageTarget::Host => 'h',
+                StorageTarget::Off  => 'o',
+            };
+            write!(w, "m{}", target)
+        }
+        Step::Repeat(_) => w.write_char('['),
+        Step::EndRepeat(_, count) => write!(w, "]x{}", count),
+    }
+}
+
+fn pin_char(pin: Pin) -> char {
+    match pin {
+        Pin::A     => 'a',
+        Pin::B     => 'b',
+        Pin::C     => 'c',
+        Pin::D     => 'd',
+        Pin::Reset => 'r',
+    }
+}
+
+fn write_text(w: &mut dyn fmt::Write, text: &[u8]) -> fmt::Result {
+    w.write_char('"')?;
+    for &c in text {
+        match c {
+            b'"' | b'\\' => write!(w, "\\{}", c as char)?,
+            0x0a         => w.write_str("\\n")?,
+            0x0d         => w.write_str("\\r")?,
+            0x09         => w.write_str("\\t")?,
+            0x03         => w.write_str("\\c")?,
+            0x04         => w.write_str("\\d")?,
+            0x1b         => w.write_str("\\e")?,
+            0x20..=0x7e  => w.write_char(c as char)?,
+            _            => w.write_char('.')?, // no escape for it, only for display
+        }
+    }
+    w.write_char('"')
+}
+
+// durations are written with the largest unit that represents them exactly
+fn write_duration(w: &mut dyn fmt::Write, us: u32) -> fmt::Result {
+    if us.is_multiple_of(1_000_000) {
+        write!(w, "{}s", us / 1_000_000)
+    } else if us.is_multiple_of(1_000) {
+        write!(w, "{}ms", us / 1_000)
+    } else {
+        write!(w, "{}us", us)
+    }
+}
+
+struct Parser<'a> {
+    sequence: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.peek_at(0)
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let ch = self.peek();
+        if ch.is_some() {
+            self.pos += 1;
+        }
+        ch
+    }
+
+    // next character without case folding, for quoted strings
+    fn next_raw(&mut self) -> Option<u8> {
+        let ch = self.peek();
+        if ch.is_some() {
+            self.pos += 1;
+            return Some(self.sequence[self.pos - 1]);
+        }
+        ch
+    }
+
+    // error pointing at the last consumed character
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError { column: self.pos.max(1), kind }
+    }
+
+    fn expect(&mut self) -> Result<u8, ParseError> {
+        match self.next() {
+            Some(ch) => Ok(ch),
+            None => Err(ParseError { column: self.pos + 1, kind: ParseErrorKind::UnexpectedEnd }),
+        }
+    }
+
+    // the name after @, resolved to the pin it is an alias of
+    fn alias(&mut self, aliases: &Aliases) -> Result<Pin, ParseError> {
+        let start = self.pos;
+        while let Some(ch) = self.peek() {
+            if !(ch.is_ascii_alphanumeric() || ch == b'-' || ch == b'_') {
+                break;
+            }
+            self.pos += 1;
+        }
+        alias_pin(aliases, &self.sequence[start..self.pos])
+            .ok_or(ParseError { column: start + 1, kind: ParseErrorKind::UnknownAlias })
+    }
+
+    // a pin followed by a state to set, by ? and a level to wait for, or by ~
+    // and a PWM
+    fn pin_step(&mut self, pin: Pin) -> Result<Step, ParseError> {
+        if self.peek() == Some(b'~') {
+            self.pos += 1;
+            return self.pwm(pin);
+        }
+        if self.peek() != Some(b'?') {
+            return Ok(Step::Set(pin, self.pin_state()?));
+        }
+        self.pos += 1;
+        let high = match self.expect()? {
+            b'h' => true,
+            b'l' => false,
+            ch => return Err(self.error(ParseErrorKind::UnknownLevel(ch))),
+        };
+        Ok(Step::Level(pin, high, self.timeout()?))
+    }
+
+    // the pin states after a (, up to the matching )
+    fn pin_group(&mut self, aliases: &Aliases) -> Result<PinStates, ParseError> {
+        let column = self.pos;
+        let mut states: PinStates = [None; 5];
+        loop {
+            let pin = match self.next() {
+                Some(b')') if states.iter().any(Option::is_some) => return Ok(states),
+                Some(b',') => continue,
+                Some(b'a') => Pin::A,
+                Some(b'b') => Pin::B,
+                Some(b'c') => Pin::C,
+                Some(b'd') => Pin::D,
+                Some(b'r') => Pin::Reset,
+                Some(b'@') => {
+                    let pin = self.alias(aliases)?;
+                    if self.next() != Some(b':') {
+                        return Err(ParseError { column: self.pos, kind: ParseErrorKind::ExpectedAliasState });
+                    }
+                    pin
+                }
+                Some(ch) => return Err(self.error(ParseErrorKind::ExpectedPinSet(ch))),
+                None => return Err(ParseError { column, kind: ParseErrorKind::UnclosedParen }),
+            };
+            let index = pin_index(pin);
+            if states[index].is_some() {
+                return Err(self.error(ParseErrorKind::DuplicatePin));
+            }
+            states[index] = Some(self.pin_state()?);
+        }
+    }
+
+    // a frequency in Hz with an optional k for kHz, : and a duty cycle in %
+    fn pwm(&mut self, pin: Pin) -> Result<Step, ParseError> {
+        let column = self.pos + 1;
+        let mut hz = self.number()?;
+        if self.peek() == Some(b'k') {
+            self.pos += 1;
+            hz = hz.saturating_mul(1000);
+        }
+        if self.next() != Some(b':') {
+            return Err(self.error(ParseErrorKind::ExpectedDuty));
+        }
+        let duty = self.number()?;
+        if hz == 0 || hz > MAX_PWM_FREQUENCY || duty > 100 {
+            return Err(ParseError { column, kind: ParseErrorKind::InvalidPwm });
+        }
+        Ok(Step::Pwm(pin, hz, duty as u8))
+    }
+
+    fn pin_state(&mut self) -> Result<PinState, ParseError> {
+        let ch = self.expect()?;
+        pin_state_from_letter(ch).ok_or(self.error(ParseErrorKind::UnknownPinState(ch)))
+    }
+
+    fn power_state(&mut self) -> Result<bool, ParseError> {
+        match self.expect()? {
+            b'1' => Ok(true),
+            b'0' => Ok(false),
+            ch => Err(self.error(ParseErrorKind::UnknownPowerState(ch))),
+        }
+    }
+
+    fn storage_target(&mut self) -> Result<StorageTarget, ParseError> {
+        match self.expect()? {
+            b'd' => Ok(StorageTarget::Dut),
+            b'h' => Ok(StorageTarget::Host),
+            b'o' => Ok(StorageTarget::Off),
+            ch => Err(self.error(ParseErrorKind::UnknownStorageTarget(ch))),
+        }
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<u8> {
+        match self.sequence.get(self.pos + offset) {
+            None | Some(b'\0') => None,
+            Some(ch) => Some(ch.to_ascii_lowercase()),
+        }
+    }
+
+    // a number with an optional us, ms or s unit, or a number of 100ms
+    // periods without unit, returned in microseconds
+    fn duration(&mut self) -> Result<u32, ParseError> {
+        let n = self.number()?;
+        let scale = match (self.peek(), self.peek_at(1)) {
+            (Some(b'u'), Some(b's')) => { self.pos += 2; 1 }
+            (Some(b'm'), Some(b's')) => { self.pos += 2; 1_000 }
+            // 5s"..." could be 5s and a quote, or 500ms and a send step
+            (Some(b's'), Some(b'"')) => return Err(ParseError { column: self.pos + 1, kind: ParseErrorKind::AmbiguousSend }),
+            (Some(b's'), _) => { self.pos += 1; 1_000_000 }
+            _ => 100_000,
+        };
+        n.checked_mul(scale).ok_or(self.error(ParseErrorKind::NumberTooLarge))
+    }
+
+    fn timeout(&mut self) -> Result<u32, ParseError> {
+        if self.peek() == Some(b't') {
+            self.pos += 1;
+            return self.duration();
+        }
+        Ok(DEFAULT_TIMEOUT_US)
+    }
+
+    // a quoted string, unescaped and stored in the program text area
+    fn text(&mut self, program: &mut Program) -> Result<Text, ParseError> {
+        if self.expect()? != b'"' {
+            return Err(self.error(ParseErrorKind::ExpectedQuote));
+        }
+        let start = program.text.len();
+        loop {
+            let ch = match self.next_raw() {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    match self.next_raw() {
+                        // sequences wait with w steps
+                        Some(ch) => escaped_char(ch).ok_or(self.error(ParseErrorKind::WaitEscape))?,
+                        None => return Err(self.error(ParseErrorKind::UnexpectedEnd)),
+                    }
+                }
+                Some(ch) => ch,
+                None => return Err(ParseError { column: self.pos + 1, kind: ParseErrorKind::UnexpectedEnd }),
+            };
+            if program.text.push(ch).is_err() {
+                return Err(self.error(ParseErrorKind::TextTooLong));
+            }
+        }
+        let len = program.text.len() - start;
+        if len == 0 {
+            return Err(self.error(ParseErrorKind::EmptyText));
+        }
+        Ok(Text { start: start as u16, len: len as u16 })
+    }
+
+    fn threshold(&mut self, quantity: Quantity) -> Result<Step, ParseError> {
+        let above = match self.expect()? {
+            b'>' => true,
+            b'<' => false,
+            ch => return Err(self.error(ParseErrorKind::ExpectedComparison(ch))),
+        };
+        let value = self.decimal()?;
+        let timeout = self.timeout()?;
+        if above {
+            Ok(Step::Above(quantity, value, timeout))
+        } else {
+            Ok(Step::Below(quantity, value, timeout))
+        }
+    }
+
+    // a natural number with an optional fractional part, i.e. 0.25
+    fn decimal(&mut self) -> Result<f32, ParseError> {
+        let mut value = self.number()? as f32;
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            let mut scale = 0.1;
+            while let Some(ch @ b'0'..=b'9') = self.peek() {
+                self.pos += 1;
+                value += (ch - b'0') as f32 * scale;
+                scale /= 10.0;
+            }
+        }
+        Ok(value)
+    }
+
+    // the x and iteration count following a ]
+    fn repeat_count(&mut self) -> Result<u16, ParseError> {
+        if self.next() != Some(b'x') {
+            return Err(self.error(ParseErrorKind::ExpectedRepeat));
+        }
+        match self.number()? {
+            count @ 1..=0xffff => Ok(count as u16),
+            _ => Err(self.error(ParseErrorKind::InvalidRepeatCount)),
+        }
+    }
+
+    fn number(&mut self) -> Result<u32, ParseError> {
+        let mut value: u32 = 0;
+        let mut digits = 0;
+        while let Some(ch @ b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+            digits += 1;
+            value = value.checked_mul(10)
+                         .and_then(|v| v.checked_add((ch - b'0') as u32))
+                         .ok_or(self.error(ParseErrorKind::NumberTooLarge))?;
+        }
+        if digits == 0 {
+            return Err(ParseError { column: self.pos + 1, kind: ParseErrorKind::MissingNumber });
+        }
+        Ok(value)
+    }
+}
+
+/// Returns the character of the escape sequence \c, as accepted by the send
+/// command and the strings of sequences, or None for \w, with which the send
+/// command waits a second.
+pub fn escaped_char(c: u8) -> Option<u8> {
+    match c {
+        b'n' => Some(0x0a),
+        b'r' => Some(0x0d),
+        b't' => Some(0x09),
+        b'a' => Some(0x07), // alert
+        b'b' => Some(0x08), // backspace
+        b'e' => Some(0x1b), // escape
+        b'c' => Some(0x03), // CTRL+C
+        b'd' => Some(0x04), // CTRL+D
+        b'w' => None,       // wait
+        _ => Some(c),       // \\ and \" included
+    }
+}
+
+pub const MAX_PATTERN: usize = MAX_TEXT;
+
+/// Streaming substring matcher (Knuth-Morris-Pratt), fed one byte at a time
+/// with the data received from the DUT serial port.
+pub struct Matcher {
+    pattern: Vec<u8, MAX_PATTERN>,
+    fail: [u8; MAX_PATTERN],
+    matched: usize,
+    found: bool,
+}
+
+impl Matcher {
+    pub fn new(pattern: &[u8]) -> Self {
+        let mut m = Matcher { pattern: Vec::new(), fail: [0; MAX_PATTERN], matched: 0, found: false };
+        let l = pattern.len().min(MAX_PATTERN);
+        m.pattern.extend_from_slice(&pattern[..l]).ok();
+
+        // fail[i] is the length of the longest proper prefix of pattern[..=i]
+        // which is also a suffix of it
+        let mut k = 0;
+        for i in 1..l {
+            while k > 0 && m.pattern[i] != m.pattern[k] {
+                k = m.fail[k - 1] as usize;
+            }
+            if m.pattern[i] == m.pattern[k] {
+                k += 1;
+            }
+            m.fail[i] = k as u8;
+        }
+        m
+    }
+
+    /// Feeds a received byte, returns true once the pattern has been seen.
+    pub fn feed(&mut self, b: u8) -> bool {
+        if self.found || self.pattern.is_empty() {
+            return self.found;
+        }
+        while self.matched > 0 && self.pattern[self.matched] != b {
+            self.matched = self.fail[self.matched - 1] as usize;
+        }
+        if self.pattern[self.matched] == b {
+            self.matched += 1;
+        }
+        if self.matched == self.pattern.len() {
+            self.found = true;
+        }
+        self.found
+    }
+
+    pub fn found(&self) -> bool {
+        self.found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    fn steps(sequence: &str) -> Vec<Step, MAX_STEPS> {
+        parse(sequence.as_bytes(), &NO_ALIASES).unwrap().steps
+    }
+
+    fn error(sequence: &str) -> ParseError {
+        parse(sequence.as_bytes(), &NO_ALIASES).err().unwrap()
+    }
+
+    // the sequence written back step by step, comma separated
+    fn written(sequence: &str) -> heapless::String<512> {
+        let program = parse(sequence.as_bytes(), &NO_ALIASES).unwrap();
+        let mut s = heapless::String::new();
+        for (i, step) in program.steps.iter().enumerate() {
+            if i > 0 {
+                s.push(',').unwrap();
+            }
+            write_step(&mut s, &program, *step).unwrap();
+        }
+        s
+    }
+
+    #[test]
+    fn pin_states() {
+        assert_eq!(steps("ah,bl,cz,do,ru,ad"), [
+            Step::Set(Pin::A, PinState::High),
+            Step::Set(Pin::B, PinState::Low),
+            Step::Set(Pin::C, PinState::Floating),
+            Step::Set(Pin::D, PinState::OpenDrainLow),
+            Step::Set(Pin::Reset, PinState::PullUp),
+            Step::Set(Pin::A, PinState::PullDown),
+        ]);
+    }
+
+    #[test]
+    fn case_insensitive() {
+        assert_eq!(steps("P1,AL,rH,W2"), steps("p1,al,rh,w2"));
+    }
+
+    #[test]
+    fn power_and_wait() {
+        assert_eq!(steps("p1,w5,p0"), [Step::Power(true), Step::Wait(500_000), Step::Power(false)]);
+    }
+
+    #[test]
+    fn commas_are_separators() {
+        assert_eq!(steps(",,p1,,w1,"), [Step::Power(true), Step::Wait(100_000)]);
+        assert_eq!(steps("p1w1"), [Step::Power(true), Step::Wait(100_000)]);
+    }
+
+    #[test]
+    fn nul_terminates() {
+        assert_eq!(steps("p1,aL\0bL,garbage"), [Step::Power(true), Step::Set(Pin::A, PinState::Low)]);
+        assert!(steps("\0p1").is_empty());
+        assert!(steps("").is_empty());
+        assert!(is_empty(b"\0p1"));
+        assert!(is_empty(b""));
+        assert!(!is_empty(b"p1"));
+    }
+
+    #[test]
+    fn waits_for_conditions() {
+        let program = parse(b"e\"login:\"t60s,i<0.5,v>4.5t1s,a?ht2s,mD", &NO_ALIASES).unwrap();
+        match program.steps[0] {
+            Step::Expect(text, timeout) => {
+                assert_eq!(program.text(text), b"login:");
+                assert_eq!(timeout, 60_000_000);
+            }
+            step => panic!("unexpected {:?}", step),
+        }
+        assert_eq!(program.steps[1], Step::Below(Quantity::Current, 0.5, DEFAULT_TIMEOUT_US));
+        assert_eq!(program.steps[2], Step::Above(Quantity::Voltage, 4.5, 1_000_000));
+        assert_eq!(program.steps[3], Step::Level(Pin::A, true, 2_000_000));
+        assert_eq!(program.steps[4], Step::Storage(StorageTarget::Dut));
+    }
+
+    #[test]
+    fn escapes() {
+        let program = parse(b"e\"a\\\"b\\\\c\\n\\r\\t\\a\\b\\e\\c\\dX\"", &NO_ALIASES).unwrap();
+        match program.steps[0] {
+            Step::Expect(text, _) => assert_eq!(program.text(text), b"a\"b\\c\n\r\t\x07\x08\x1b\x03\x04X"),
+            step => panic!("unexpected {:?}", step),
+        }
+        // the text is kept as is, without case folding
+        let program = parse(b"E\"Hit Any Key\"", &NO_ALIASES).unwrap();
+        match program.steps[0] {
+            Step::Expect(text, _) => assert_eq!(program.text(text), b"Hit Any Key"),
+            step => panic!("unexpected {:?}", step),
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        let mut aliases = NO_ALIASES;
+        aliases[pin_index(Pin::A)][..3].copy_from_slice(b"REC");
+        let program = parse(b"@rec:l,@REC?h", &aliases).unwrap();
+        assert_eq!(program.steps, [Step::Set(Pin::A, PinState::Low), Step::Level(Pin::A, true, DEFAULT_TIMEOUT_US)]);
+        assert_eq!(parse(b"@pgood:l", &aliases).err().unwrap(), ParseError { column: 2, kind: ParseErrorKind::UnknownAlias });
+        assert_eq!(parse(b"@rec", &aliases).err().unwrap(), ParseError { column: 5, kind: ParseErrorKind::ExpectedAliasState });
+    }
+
+    #[test]
+    fn error_columns() {
+        assert_eq!(error("p1,x1"), ParseError { column: 4, kind: ParseErrorKind::UnknownOpcode(b'x') });
+        assert_eq!(error("p1,aX"), ParseError { column: 5, kind: ParseErrorKind::UnknownPinState(b'x') });
+        assert_eq!(error("p2"), ParseError { column: 2, kind: ParseErrorKind::UnknownPowerState(b'2') });
+        assert_eq!(error("p1,w"), ParseError { column: 5, kind: ParseErrorKind::MissingNumber });
+        assert_eq!(error("p1,a"), ParseError { column: 5, kind: ParseErrorKind::UnexpectedEnd });
+        assert_eq!(error("w99999999999"), ParseError { column: 11, kind: ParseErrorKind::NumberTooLarge });
+        assert_eq!(error("w99999"), ParseError { column: 6, kind: ParseErrorKind::NumberTooLarge });
+        assert_eq!(error("e\"\""), ParseError { column: 3, kind: ParseErrorKind::EmptyText });
+        assert_eq!(error("e\"abc"), ParseError { column: 6, kind: ParseErrorKind::UnexpectedEnd });
+        assert_eq!(error("eabc"), ParseError { column: 2, kind: ParseErrorKind::ExpectedQuote });
+        assert_eq!(error("i=1"), ParseError { column: 2, kind: ParseErrorKind::ExpectedComparison(b'=') });
+        assert_eq!(error("mx"), ParseError { column: 2, kind: ParseErrorKind::UnknownStorageTarget(b'x') });
+        assert_eq!(error("a?x"), ParseError { column: 3, kind: ParseErrorKind::UnknownLevel(b'x') });
+    }
+
+    #[test]
+    fn error_display() {
+        let mut s: heapless::String<64> = heapless::String::new();
+        write!(s, "{}", error("p1,x1")).unwrap();
+        assert_eq!(s, "column 4: unknown opcode 'x'");
+    }
+
+    #[test]
+    fn step_limit() {
+        let mut sequence: heapless::String<512> = heapless::String::new();
+        for _ in 0..MAX_STEPS {
+            sequence.push_str("al,").unwrap();
+        }
+        assert_eq!(steps(&sequence).len(), MAX_STEPS);
+        sequence.push_str("bl").unwrap();
+        assert_eq!(error(&sequence), ParseError { column: sequence.len(), kind: ParseErrorKind::TooManySteps });
+    }
+
+    #[test]
+    fn text_limit() {
+        let mut sequence: heapless::String<512> = heapless::String::new();
+        sequence.push_str("e\"").unwrap();
+        for _ in 0..MAX_TEXT {
+            sequence.push('x').unwrap();
+        }
+        sequence.push('"').unwrap();
+        assert_eq!(steps(&sequence).len(), 1);
+        sequence.push_str(",e\"y\"").unwrap();
+        assert_eq!(error(&sequence).kind, ParseErrorKind::TextTooLong);
+    }
+
+    #[test]
+    fn write_step_round_trip() {
+        for sequence in [
+            "p1,al,rl,w100ms,rz,w100ms",
+            "ah,bo,cu,dd,rz",
+            "e\"Hit \\\"any\\\" key\\r\\n\"t5s,i<0.050t30s,v>4.500t10s,p0",
+            "c?ht2s,r?lt10s,mh,md,mo",
+        ] {
+            let once = written(sequence);
+            assert_eq!(once, sequence);
+            assert_eq!(written(&once), once);
+        }
+    }
+
+    #[test]
+    fn duration_units() {
+        assert_eq!(steps("w250us,w20ms,w3s,w2"), [
+            Step::Wait(250),
+            Step::Wait(20_000),
+            Step::Wait(3_000_000),
+            Step::Wait(200_000),
+        ]);
+        assert_eq!(steps("W5MS,w1S"), [Step::Wait(5_000), Step::Wait(1_000_000)]);
+        assert_eq!(steps("e\"ok\"t500ms")[0], parse(b"e\"ok\"t5", &NO_ALIASES).unwrap().steps[0]);
+        assert_eq!(error("w4295s"), ParseError { column: 6, kind: ParseErrorKind::NumberTooLarge });
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration(b"500ms"), Ok(500_000));
+        assert_eq!(parse_duration(b"5"), Ok(500_000));
+        assert_eq!(parse_duration(b"2s"), Ok(2_000_000));
+        assert_eq!(parse_duration(b"100us"), Ok(100));
+        assert_eq!(parse_duration(b"5x"), Err(ParseError { column: 2, kind: ParseErrorKind::UnknownUnit(b'x') }));
+        assert_eq!(parse_duration(b"5m"), Err(ParseError { column: 2, kind: ParseErrorKind::UnknownUnit(b'm') }));
+        assert_eq!(parse_duration(b"ms"), Err(ParseError { column: 1, kind: ParseErrorKind::MissingNumber }));
+        assert_eq!(parse_duration(b""), Err(ParseError { column: 1, kind: ParseErrorKind::MissingNumber }));
+    }
+
+    #[test]
+    fn durations_written_with_largest_exact_unit() {
+        assert_eq!(written("w1500ms,w2000ms,w1,w30,w7us"), "w1500ms,w2s,w100ms,w3s,w7us");
+    }
+
+    #[test]
+    fn repeats() {
+        assert_eq!(steps("[bl,w1,bz]x3,p1"), [
+            Step::Repeat(4),
+            Step::Set(Pin::B, PinState::Low),
+            Step::Wait(100_000),
+            Step::Set(Pin::B, PinState::Floating),
+            Step::EndRepeat(0, 3),
+            Step::Power(true),
+        ]);
+        assert_eq!(steps("[al,[bl]x2]X65535"), [
+            Step::Repeat(5),
+            Step::Set(Pin::A, PinState::Low),
+            Step::Repeat(4),
+            Step::Set(Pin::B, PinState::Low),
+            Step::EndRepeat(2, 2),
+            Step::EndRepeat(0, 65535),
+        ]);
+        assert_eq!(written("[al,[bl]x2]x3"), "[,al,[,bl,]x2,]x3");
+    }
+
+    #[test]
+    fn repeat_nesting_limit() {
+        assert_eq!(steps("[[[[al]x2]x2]x2]x2").len(), 9);
+        assert_eq!(error("[[[[[al]x2]x2]x2]x2]x2"), ParseError { column: 5, kind: ParseErrorKind::NestingTooDeep });
+    }
+
+    #[test]
+    fn repeat_iterations_limit() {
+        assert_eq!(steps("[[al]x1000,[bl]x65535]x15").len(), 8);
+        assert_eq!(steps("[[[al]x10]x100]x1000").len(), 7);
+        assert_eq!(error("[[al]x1000,[bl]x65535]x16"), ParseError { column: 25, kind: ParseErrorKind::TooManyIterations });
+        assert_eq!(error("[[[al]x10]x100]x1001"), ParseError { column: 20, kind: ParseErrorKind::TooManyIterations });
+    }
+
+    #[test]
+    fn repeat_errors() {
+        assert_eq!(error("al]x2"), ParseError { column: 3, kind: ParseErrorKind::UnmatchedBracket });
+        assert_eq!(error("p1,[al,[bl]x2"), ParseError { column: 4, kind: ParseErrorKind::UnclosedBracket });
+        assert_eq!(error("[al]"), ParseError { column: 4, kind: ParseErrorKind::ExpectedRepeat });
+        assert_eq!(error("[al]y2"), ParseError { column: 5, kind: ParseErrorKind::ExpectedRepeat });
+        assert_eq!(error("[al]x"), ParseError { column: 6, kind: ParseErrorKind::MissingNumber });
+        assert_eq!(error("[al]x0"), ParseError { column: 6, kind: ParseErrorKind::InvalidRepeatCount });
+        assert_eq!(error("[al]x65536"), ParseError { column: 10, kind: ParseErrorKind::InvalidRepeatCount });
+    }
+
+    #[test]
+    fn send() {
+        let program = parse(b"p1,s\"\\c\",S\"boot\\r\"", &NO_ALIASES).unwrap();
+        assert_eq!(program.steps.len(), 3);
+        match (program.steps[1], program.steps[2]) {
+            (Step::Send(interrupt), Step::Send(boot)) => {
+                assert_eq!(program.text(interrupt), b"\x03");
+                assert_eq!(program.text(boot), b"boot\r");
+            }
+            steps => panic!("unexpected {:?}", steps),
+        }
+        assert_eq!(error("s\"a\\wb\""), ParseError { column: 5, kind: ParseErrorKind::WaitEscape });
+        assert_eq!(error("s\"\""), ParseError { column: 3, kind: ParseErrorKind::EmptyText });
+        assert_eq!(written("s\"\\c\",s\"run bootcmd\\n\""), "s\"\\c\",s\"run bootcmd\\n\"");
+    }
+
+    #[test]
+    fn send_after_duration() {
+        // s" after a number could be the seconds unit or a send step
+        assert_eq!(error("e\"Hit any key\"t5s\"\\c\""), ParseError { column: 17, kind: ParseErrorKind::AmbiguousSend });
+        assert_eq!(error("w5s\"root\""), ParseError { column: 3, kind: ParseErrorKind::AmbiguousSend });
+        let program = parse(b"e\"Hit any key\"t5s,s\"\\c\"", &NO_ALIASES).unwrap();
+        assert_eq!(program.steps.len(), 2);
+        match program.steps[0] {
+            Step::Expect(_, timeout) => assert_eq!(timeout, 5_000_000),
+            step => panic!("unexpected {:?}", step),
+        }
+        assert_eq!(steps("w5,s\"root\"")[0], Step::Wait(500_000));
+        assert_eq!(steps("w5s,w5")[0], Step::Wait(5_000_000));
+    }
+
+    #[test]
+    fn pin_groups() {
+        let mut aliases = NO_ALIASES;
+        aliases[pin_index(Pin::B)][..3].copy_from_slice(b"BTN");
+        let program = parse(b"p1,(aL,@btn:h,rl),w1", &aliases).unwrap();
+        let mut states: PinStates = [None; 5];
+        states[pin_index(Pin::A)] = Some(PinState::Low);
+        states[pin_index(Pin::B)] = Some(PinState::High);
+        states[pin_index(Pin::Reset)] = Some(PinState::Low);
+        assert_eq!(program.steps, [Step::Power(true), Step::SetPins(states), Step::Wait(100_000)]);
+        // written in PINS order
+        assert_eq!(written("(al,bh,rl)"), "(rl,al,bh)");
+    }
+
+    #[test]
+    fn pin_group_errors() {
+        assert_eq!(error("(al,al)"), ParseError { column: 5, kind: ParseErrorKind::DuplicatePin });
+        assert_eq!(error("()"), ParseError { column: 2, kind: ParseErrorKind::ExpectedPinSet(b')') });
+        assert_eq!(error("(al,w1)"), ParseError { column: 5, kind: ParseErrorKind::ExpectedPinSet(b'w') });
+        assert_eq!(error("p1,(al,bl"), ParseError { column: 4, kind: ParseErrorKind::UnclosedParen });
+        assert_eq!(error("al)"), ParseError { column: 3, kind: ParseErrorKind::UnmatchedParen });
+        assert_eq!(error("(ax)"), ParseError { column: 3, kind: ParseErrorKind::UnknownPinState(b'x') });
+    }
+
+    #[test]
+    fn pwm() {
+        let mut aliases = NO_ALIASES;
+        aliases[pin_index(Pin::C)][..3].copy_from_slice(b"CLK");
+        let program = parse(b"a~1000:50,b~20K:0,@clk~1k:100", &aliases).unwrap();
+        assert_eq!(program.steps, [
+            Step::Pwm(Pin::A, 1000, 50),
+            Step::Pwm(Pin::B, 20_000, 0),
+            Step::Pwm(Pin::C, 1000, 100),
+        ]);
+        assert_eq!(written("a~10k:25,az"), "a~10000:25,az");
+    }
+
+    #[test]
+    fn pwm_errors() {
+        assert_eq!(error("a~21k:50"), ParseError { column: 3, kind: ParseErrorKind::InvalidPwm });
+        assert_eq!(error("a~0:50"), ParseError { column: 3, kind: ParseErrorKind::InvalidPwm });
+        assert_eq!(error("a~1k:101"), ParseError { column: 3, kind: ParseErrorKind::InvalidPwm });
+        assert_eq!(error("a~1k50"), ParseError { column: 5, kind: ParseErrorKind::ExpectedDuty });
+        assert_eq!(error("a~:50"), ParseError { column: 3, kind: ParseErrorKind::MissingNumber });
+        assert_eq!(error("a~1k:"), ParseError { column: 6, kind: ParseErrorKind::MissingNumber });
+        assert_eq!(error("(a~1k:50)"), ParseError { column: 3, kind: ParseErrorKind::UnknownPinState(b'~') });
+    }
+}
@@ -3,21 +3,27 @@ use embedded_hal::digital::OutputPin;
 use core::fmt::Write;
 
 use arrayvec::ArrayString;
+use rtic::Mutex;
 
-use crate::config::ConfigArea;
-use crate::ctlpins::{PinState, CTLPinsTrait};
+use crate::config::{ConfigArea, ConfigBlock};
+use crate::ctlpins::{PinState, CTLPinsTrait, SequenceState, SetOutcome};
 use crate::powermeter::PowerMeter;
 use crate::{usbserial::*, ctlpins::CTLPins};
 use crate::storage::StorageSwitchTrait;
 use crate::version;
+use crate::sequence::{self, Aliases, Pin, PinStates, PINS};
+use crate::edgelog::{Edge, EdgeLog, Line};
+use crate::capture::{self, Capture, CaptureState, Trigger};
+use crate::i2c::{I2cError, MAX_ADDRESS, MAX_I2C_LEN};
 
 use ushell::{
     autocomplete::StaticAutocomplete, history::LRUHistory, Input as ushell_input,
     ShellError as ushell_error, UShell,
 };
-const N_COMMANDS: usize = 14;
+const N_COMMANDS: usize = 22;
 const COMMANDS: [&str; N_COMMANDS] = ["help", "about", "get-config", "version", "meter", "storage", "send",
-                                      "set", "set-config", "monitor", "power", "console", "status", "clear"];
+                                      "set", "set-config", "monitor", "power", "console", "status", "clear",
+                                      "run", "seq", "get", "pulse", "pins", "capture", "pwm", "i2c"];
 pub type ShellType = UShell<USBSerialType, StaticAutocomplete<N_COMMANDS>, LRUHistory<512, 10>, 512>;
 pub struct ShellStatus {
     pub monitor_enabled: bool,
@@ -32,13 +38,34 @@ pub const HELP: &str = "\r\n\
         about               : print information about this device\r\n\
         clear               : clear the screen\r\n\
         help                : print this help\r\n\
-        meter on|read|off   : read power consumption\r\n\
+        meter on|read|off   : read power consumption, and the energy used since the last reset\r\n\
+        meter reset         : reset the energy counter, i.e. when the DUT boots or a test starts\r\n\
         monitor on|off      : enable or disable the serial console monitor in this terminal\r\n\
         console             : enter into serial console mode, exit with CTRL+A 5 times\r\n\
-        power on|off        : power on or off the DUT\r\n\
+        power on|off|abort  : power on or off the DUT, or abort a running sequence\r\n\
+        run name            : run a stored sequence\r\n\
+        seq list|show|set|del [name] [sequence] : manage stored sequences\r\n\
+        seq trace|dry-run name|sequence : run a sequence printing every step, dry-run only prints the steps\r\n\
         send string         : send string to the DUT\r\n\
-        set r|a|b|c|d l|h|z : set RESET, CTL_A,B,C or D to low, high or high impedance\r\n\
-        set-config name|tags|json|usb_console|poweron|poweroff value : set the config value in flash\r\n\
+        set r|a|b|c|d l|h|z|o|u|d : set RESET, CTL_A,B,C or D to low, high, high impedance,\r\n\
+                              open-drain low, pull-up or pull-down\r\n\
+        set a l b l r l     : set several pins together, the output levels change at once\r\n\
+        pulse r|a|b|c|d l|h|z|o|u|d duration : set a pin for the duration, i.e. 500ms, then return it to its state\r\n\
+        pwm r|a|b|c|d freq duty : drive a pin with a PWM, i.e. 1k 50, setting the pin stops it\r\n\
+        get [r|a|b|c|d]     : print the state set and the input level of RESET, CTL_A,B,C or D\r\n\
+        pins log [start [r|a|b|c|d]...|stop|clear] : capture the edges on the CTL pins and the power, or print them\r\n\
+        pins log index      : print the edges from index on, when they don't fit in a single page\r\n\
+        capture start rate [r|a|b|c|d|power][+|-|*] : sample the CTL pins and the power at rate Hz, i.e. 10k,\r\n\
+                              once the pin or power rises (+), falls (-) or changes (*)\r\n\
+        capture [stop|vcd]  : print the capture state, stop it, or print the samples as VCD for sigrok\r\n\
+        capture vcd index   : print the VCD from sample index on, when it doesn't fit in a single page\r\n\
+        i2c scan|read|write : scan the I2C bus on the pins set with set-config i2c, read [reg] len bytes\r\n\
+                              or write bytes at an address, i.e. i2c read 0x50 0x00 16, the DUT must be on\r\n\
+        set-config name|tags|json|usb_console value : set the config value in flash\r\n\
+        set-config power_on|power_off|power_rescue sequence : set the sequence of power on, off or rescue\r\n\
+        set-config alias_r|alias_a|alias_b|alias_c|alias_d name : name a pin, usable instead of its letter\r\n\
+        set-config boot_r|boot_a|boot_b|boot_c|boot_d l|h|z|o|u|d : set the pin state applied at boot\r\n\
+        set-config i2c scl sda : set the pins of the I2C master, i.e. c d\r\n\
         get-config          : print all the config parameters\r\n\
         status              : print status of the device\r\n\
         storage dut|host|off: connect storage to DUT, host or disconnect\r\n\
@@ -60,18 +87,22 @@ pub fn new(serial:USBSerialType) -> ShellType {
     shell
 }
 
-pub fn handle_shell_commands<L, S, P>(shell: &mut ShellType,
+pub fn handle_shell_commands<L, S, P, E, K>(shell: &mut ShellType,
                                       shell_status: &mut ShellStatus,
                                       led_cmd: &mut L,
                                       storage: &mut S,
                                       ctl_pins:&mut CTLPins<P>,
                                       send_to_dut: &mut dyn FnMut(&[u8]),
                                       power_meter: &mut dyn PowerMeter,
-                                      config: &mut ConfigArea)
+                                      config: &mut ConfigArea,
+                                      edge_log: &mut E,
+                                      capture: &mut K)
 where
     L: OutputPin,
     S: StorageSwitchTrait,
     P: OutputPin,
+    E: Mutex<T = EdgeLog>,
+    K: Mutex<T = Capture>,
 {
     loop {
         let mut response = ArrayString::<512>::new();
@@ -95,10 +126,29 @@ where
                         "storage" =>    { handle_storage_cmd(&mut response, args, storage); }
                         "power" =>      { handle_power_cmd(&mut response, args, ctl_pins, config); }
                         "send" =>       { handle_send_cmd(&mut response, args, send_to_dut); }
-                        "set" =>        { handle_set_cmd(&mut response, args, ctl_pins); }
-                        "set-config" => { handle_set_config_cmd(&mut response, args, config); }
+                        "set" =>        { handle_set_cmd(&mut response, args, ctl_pins, config); }
+                        "get" =>        { handle_get_cmd(&mut response, args, ctl_pins, config); }
+                        "pulse" =>      { handle_pulse_cmd(&mut response, args, ctl_pins, config); }
+                        "pwm" =>        { handle_pwm_cmd(&mut response, args, ctl_pins, config); }
+                        "i2c" =>        { handle_i2c_cmd(&mut response, args, ctl_pins, config); }
+                        "pins" =>       {
+                                          // args borrows the shell, which the log is written to
+                                          match ArrayString::<64>::from(args) {
+                                              Ok(args) => handle_pins_cmd(&mut response, shell, &args, edge_log, config),
+                                              Err(_) => { write!(response, "Error: argument too long").ok(); }
+                                          }
+                                        }
+                        "capture" =>    {
+                                          match ArrayString::<64>::from(args) {
+                                              Ok(args) => handle_capture_cmd(&mut response, shell, &args, capture, config),
+                                              Err(_) => { write!(response, "Error: argument too long").ok(); }
+                                          }
+                                        }
+                        "set-config" => { handle_set_config_cmd(&mut response, args, ctl_pins, config); }
+                        "run" =>        { handle_run_cmd(&mut response, args, ctl_pins, config); }
+                        "seq" =>        { handle_seq_cmd(&mut response, args, ctl_pins, config); }
                         "get-config" => { handle_get_config_cmd(&mut response, args, config); }
-                        "status" =>     { handle_status_cmd(&mut response, args, shell_status); }
+                        "status" =>     { handle_status_cmd(&mut response, args, shell_status, ctl_pins, config); }
                         "version" =>    { version::write_version(&mut response); }
                         "" =>           {}
                         _ =>            { write!(shell, "{0:}unsupported command{0:}", CR).ok(); }
@@ -125,23 +175,108 @@ where
     C: CTLPinsTrait,
     B: Write
  {
-    if args == "on" {
-        ctlpins.power_on(&config.get().power_on);
-        write!(response, "Device powered on").ok();
+    let seqs = config.sequences();
+    let result = if args == "on" {
+        ctlpins.power_stored(true, &seqs.power_on).map(|e| (e, "Device powered on"))
     } else if args == "off" {
-        ctlpins.power_off(&config.get().power_off);
-        write!(response, "Device powered off").ok();
+        ctlpins.power_stored(false, &seqs.power_off).map(|e| (e, "Device powered off"))
     } else if args == "force-off" {
-        ctlpins.power_off(&[0u8; 0]);
-        write!(response, "Device forced off").ok();
+        ctlpins.power_off(&[0u8; 0]).map(|_| (None, "Device forced off"))
     } else if args == "force-on" {
-        ctlpins.power_on(&[0u8; 0]);
-        write!(response, "Device forced on").ok();
+        ctlpins.power_on(&[0u8; 0]).map(|_| (None, "Device forced on"))
     } else if args == "rescue" {
-        ctlpins.power_on(&config.get().power_rescue);
-        write!(response, "Device powered on to rescue").ok();
+        ctlpins.power_stored(true, &seqs.power_rescue).map(|e| (e, "Device powered on to rescue"))
+    } else if args == "abort" {
+        if ctlpins.abort_sequence() {
+            write!(response, "Sequence aborted, ").ok();
+            write_power_state(response, ctlpins, &config.get().aliases);
+        } else {
+            write!(response, "No sequence in progress").ok();
+        }
+        return;
     } else {
-        write!(response, "usage: power on|off|force-on|force-off|rescue").ok();
+        write!(response, "usage: power on|off|force-on|force-off|rescue|abort").ok();
+        return;
+    };
+
+    match result {
+        Ok((None, msg)) => { write!(response, "{}", msg).ok(); }
+        Ok((Some(e), msg)) => {
+            write!(response, "Error: invalid stored sequence, {}, switched the power directly{}{}", e, CR, msg).ok();
+        }
+        Err(e) => { write!(response, "Error: {}", e).ok(); }
+    }
+}
+
+fn write_power_state<B, C>(response:&mut B, ctlpins: &C, aliases: &Aliases)
+where
+    C: CTLPinsTrait,
+    B: Write
+ {
+    write!(response, "power {}", if ctlpins.is_on() { "on" } else { "off" }).ok();
+    for pin in PINS {
+        write!(response, ", ").ok();
+        write_pin_name(response, pin, aliases);
+        write!(response, ": {}", pin_state_name(ctlpins.stored_state(pin))).ok();
+    }
+}
+
+// a pin letter or a pin alias from the config
+fn pin_from_name(name: &str, aliases: &Aliases) -> Option<Pin> {
+    match name {
+        "r" => Some(Pin::Reset),
+        "a" => Some(Pin::A),
+        "b" => Some(Pin::B),
+        "c" => Some(Pin::C),
+        "d" => Some(Pin::D),
+        _   => sequence::alias_pin(aliases, name.as_bytes()),
+    }
+}
+
+// the pin name followed by its alias, if any, i.e. CTL_A (REC)
+fn write_pin_name<B>(response:&mut B, pin: Pin, aliases: &Aliases)
+where
+    B: Write
+ {
+    write!(response, "{}", pin_name(pin)).ok();
+    let alias = &aliases[sequence::pin_index(pin)];
+    if alias[0] != 0 {
+        write!(response, " (").ok();
+        write_u8(response, alias);
+        write!(response, ")").ok();
+    }
+}
+
+fn pin_name(pin: Pin) -> &'static str {
+    match pin {
+        Pin::Reset => "/RESET",
+        Pin::A     => "CTL_A",
+        Pin::B     => "CTL_B",
+        Pin::C     => "CTL_C",
+        Pin::D     => "CTL_D",
+    }
+}
+
+fn pin_state_name(state: PinState) -> &'static str {
+    match state {
+        PinState::Low      => "LOW",
+        PinState::High     => "HIGH",
+        PinState::Floating => "HIGH IMPEDANCE",
+        PinState::OpenDrainLow => "OPEN-DRAIN LOW",
+        PinState::PullUp   => "PULL-UP",
+        PinState::PullDown => "PULL-DOWN",
+    }
+}
+
+fn pin_state_from_name(name: &str) -> Option<PinState> {
+    match name {
+        "l" => Some(PinState::Low),
+        "h" => Some(PinState::High),
+        "z" => Some(PinState::Floating),
+        "o" => Some(PinState::OpenDrainLow),
+        "u" => Some(PinState::PullUp),
+        "d" => Some(PinState::PullDown),
+        _   => None,
     }
 }
 
@@ -184,11 +319,14 @@ where
         write!(response, "Power meter monitoring enabled").ok();
     } else if args == "read" {
         power_meter.write(response);
+    } else if args == "reset" {
+        power_meter.reset_energy();
+        write!(response, "Energy counter reset").ok();
     } else if args == "off" {
         shell_status.meter_enabled = false;
         write!(response, "Power monitor disabled").ok();
     } else {
-        write!(response, "usage: meter on|read|off").ok();
+        write!(response, "usage: meter on|read|reset|off").ok();
     }
 }
 
@@ -219,74 +357,476 @@ where
     }
 }
 
-fn handle_set_cmd<B, C>(response:&mut B, args: &str, ctl_pins:&mut C)
+fn handle_get_cmd<B, C>(response:&mut B, args: &str, ctl_pins:&mut C, config: &ConfigArea)
 where
     B: Write,
     C: CTLPinsTrait
+ {
+    let aliases = config.get().aliases;
+    let write_pin = |response: &mut B, pin: Pin| {
+        write_pin_name(response, pin, &aliases);
+        match ctl_pins.stored_pwm() {
+            Some((p, hz, duty)) if p == pin => { write!(response, ": set PWM {}Hz {}%", hz, duty).ok(); }
+            _ => { write!(response, ": set {}", pin_state_name(ctl_pins.stored_state(pin))).ok(); }
+        }
+        if ctl_pins.stored_outcome(pin) == SetOutcome::Deferred {
+            write!(response, " (deferred until power on)").ok();
+        }
+        write!(response, ", input {}", if ctl_pins.input_level(pin) { "HIGH" } else { "LOW" }).ok();
+    };
+    if args == "" {
+        for (i, pin) in PINS.iter().enumerate() {
+            if i > 0 {
+                write!(response, "{}", CR).ok();
+            }
+            write_pin(response, *pin);
+        }
+    } else if let Some(pin) = pin_from_name(args, &aliases) {
+        write_pin(response, pin);
+    } else {
+        write!(response, "usage: get [r|a|b|c|d|alias]").ok();
+    }
+}
 
+fn handle_pulse_cmd<B, C>(response:&mut B, args: &str, ctl_pins:&mut C, config: &ConfigArea)
+where
+    B: Write,
+    C: CTLPinsTrait
  {
+    let aliases = config.get().aliases;
+    let mut split_args = args.split_whitespace();
+    let (pin, state, duration) = match (split_args.next(), split_args.next(), split_args.next(), split_args.next()) {
+        (Some(pin), Some(state), Some(duration), None) => (pin, state, duration),
+        _ => {
+            write!(response, "usage: pulse r|a|b|c|d|alias l|h|z|o|u|d duration").ok();
+            return;
+        }
+    };
+    let pin = match pin_from_name(pin, &aliases) {
+        Some(pin) => pin,
+        None => {
+            write!(response, "Unknown pin {}, expected r, a, b, c, d or an alias", pin).ok();
+            return;
+        }
+    };
+    let state = match pin_state_from_name(state) {
+        Some(state) => state,
+        None => {
+            write!(response, "Unknown state {}, expected l, h, z, o, u or d", state).ok();
+            return;
+        }
+    };
+    let us = match sequence::parse_duration(duration.as_bytes()) {
+        Ok(us) => us,
+        Err(e) => {
+            write!(response, "Invalid duration {}, {}", duration, e).ok();
+            return;
+        }
+    };
+    match ctl_pins.pulse(pin, state, us) {
+        Ok(SetOutcome::Applied) => {
+            write!(response, "Pulsing ").ok();
+            write_pin_name(response, pin, &aliases);
+            write!(response, " {} for {}", pin_state_name(state), duration).ok();
+        }
+        Ok(SetOutcome::Deferred) => {
+            // driving the pin would back-power the DUT
+            write!(response, "Pulsing ").ok();
+            write_pin_name(response, pin, &aliases);
+            write!(response, " {} for {}, the DUT is off", pin_state_name(PinState::Floating), duration).ok();
+        }
+        Err(e) => { write!(response, "Error: {}", e).ok(); }
+    }
+}
 
-    if args.len() == 3 && args.as_bytes()[1] == ' ' as u8{
-        let mut chars = args.chars();
-        let ctl     = chars.next().unwrap();
-        let _space  = chars.next().unwrap();
-        let val     = chars.next().unwrap();
+// edges printed by a pins log command, so they fit in the USB serial buffer
+const EDGE_PAGE_LEN: usize = 10;
 
-        if ctl != 'r' && ctl != 'a' && ctl != 'b' && ctl != 'c' && ctl != 'd' {
-            write_set_usage(response);
+fn handle_pins_cmd<B, E>(response:&mut B, shell: &mut ShellType, args: &str, edge_log: &mut E, config: &ConfigArea)
+where
+    B: Write,
+    E: Mutex<T = EdgeLog>,
+ {
+    let aliases = config.get().aliases;
+    let mut split_args = args.split_whitespace();
+    match (split_args.next(), split_args.next()) {
+        (Some("log"), Some("start")) => {
+            let mut mask = 0;
+            for name in split_args {
+                match pin_from_name(name, &aliases) {
+                    Some(pin) => mask |= 1 << sequence::pin_index(pin),
+                    None => {
+                        write!(response, "Unknown pin {}, expected r, a, b, c, d or an alias", name).ok();
+                        return;
+                    }
+                }
+            }
+            if mask == 0 {
+                mask = (1 << PINS.len()) - 1;
+            }
+            edge_log.lock(|edge_log| edge_log.start(mask));
+            write!(response, "Capturing edges").ok();
+        }
+        (Some("log"), Some("stop")) => {
+            edge_log.lock(|edge_log| edge_log.stop());
+            write!(response, "Capture stopped").ok();
+        }
+        (Some("log"), Some("clear")) => {
+            edge_log.lock(|edge_log| edge_log.clear());
+            write!(response, "Edge log cleared").ok();
+        }
+        (Some("log"), index) => {
+            let index = match index.map_or(Ok(0), |index| index.parse::<usize>()) {
+                Ok(index) => index,
+                Err(_) => {
+                    write!(response, "usage: pins log [index|start [r|a|b|c|d|alias]...|stop|clear]").ok();
+                    return;
+                }
+            };
+            // a page of edges is copied from the log, so the interrupts capturing
+            // them are not delayed while it is printed
+            let mut page: heapless::Vec<Edge, EDGE_PAGE_LEN> = heapless::Vec::new();
+            let mut first = None;
+            let mut last = [None; 6]; // previous edge of each line, power and PINS order
+            let (count, lost, capturing) = edge_log.lock(|edge_log| {
+                for (i, edge) in edge_log.edges().enumerate() {
+                    first.get_or_insert(edge.us);
+                    if i < index {
+                        last[line_index(edge.line)] = Some(edge.us);
+                    } else if page.push(*edge).is_err() {
+                        break;
+                    }
+                }
+                (edge_log.edges().count(), edge_log.lost(), edge_log.is_capturing())
+            });
+            for edge in &page {
+                let start = first.unwrap_or(edge.us);
+                let line = line_index(edge.line);
+                let mut name = ArrayString::<40>::new();
+                match edge.line {
+                    Line::Power => { write!(name, "POWER").ok(); }
+                    Line::Pin(pin) => write_pin_name(&mut name, pin, &aliases),
+                }
+                write!(shell, "{}", CR).ok();
+                write_ms(shell, edge.us - start);
+                write!(shell, " {:<24} {:<8}", name.as_str(), if edge.rising { "rising" } else { "falling" }).ok();
+                if let Some(previous) = last[line] {
+                    // i.e. how long a line was held low
+                    write!(shell, "+").ok();
+                    write_ms(shell, edge.us - previous);
+                }
+                last[line] = Some(edge.us);
+            }
+            let state = if capturing { "capturing" } else { "stopped" };
+            // the edges are timestamped by the monotonic timer, see main.rs
+            write!(response, "{} edges captured, {} lost, {}, timed with a 100us resolution", count, lost, state).ok();
+            if index + page.len() < count {
+                write!(response, ", more with pins log {}", index + page.len()).ok();
+            }
+        }
+        _ => { write!(response, "usage: pins log [index|start [r|a|b|c|d|alias]...|stop|clear]").ok(); }
+    }
+}
+
+// bytes of VCD printed by a capture vcd command, so they fit in the USB serial buffer
+const VCD_PAGE_LEN: usize = 640;
+
+fn handle_capture_cmd<B, K>(response:&mut B, shell: &mut ShellType, args: &str, capture: &mut K, config: &ConfigArea)
+where
+    B: Write,
+    K: Mutex<T = Capture>,
+ {
+    let aliases = config.get().aliases;
+    let mut split_args = args.split_whitespace();
+    match (split_args.next(), split_args.next(), split_args.next(), split_args.next()) {
+        (None, _, _, _) => {
+            let (state, len, rate, trigger) = capture.lock(|capture| {
+                (capture.state(), capture.len(), capture.rate(), capture.trigger_index())
+            });
+            let state = match state {
+                CaptureState::Idle      => "idle",
+                CaptureState::Armed     => "waiting for the trigger",
+                CaptureState::Triggered => "triggered",
+                CaptureState::Done      => "done",
+            };
+            write!(response, "Capture {}, {} samples at {}Hz", state, len, rate).ok();
+            if let Some(index) = trigger {
+                write!(response, ", trigger at sample {}", index).ok();
+            }
+        }
+        (Some("start"), Some(rate), trigger, None) => {
+            let rate = match parse_rate(rate) {
+                Some(rate) => rate,
+                None => {
+                    write!(response, "Invalid rate {}, i.e. 1000 or 10k", rate).ok();
+                    return;
+                }
+            };
+            let trigger = match trigger.map(|t| parse_trigger(t, &aliases)) {
+                None => Trigger::Now,
+                Some(Some(trigger)) => trigger,
+                Some(None) => {
+                    write!(response, "Invalid trigger, expected a pin, an alias or power followed by +, - or *").ok();
+                    return;
+                }
+            };
+            match capture.lock(|capture| capture.start(rate, trigger)) {
+                Ok(()) => { write!(response, "Capture started at {}Hz", rate).ok(); }
+                Err(e) => { write!(response, "Error: {}", e).ok(); }
+            }
+        }
+        (Some("stop"), None, _, _) => {
+            let len = capture.lock(|capture| {
+                capture.stop();
+                capture.len()
+            });
+            write!(response, "Capture stopped, {} samples", len).ok();
+        }
+        (Some("vcd"), index, None, _) => {
+            let index = match index.map_or(Ok(0), |index| index.parse::<usize>()) {
+                Ok(index) => index,
+                Err(_) => {
+                    write!(response, "usage: capture [start rate [r|a|b|c|d|alias|power][+|-|*]|stop|vcd [index]]").ok();
+                    return;
+                }
+            };
+            // the samples do not fit in the response, they are written as they are formatted
+            write!(shell, "{}", CR).ok();
+            if let Some(next) = capture::write_vcd(capture, shell, &aliases, index, VCD_PAGE_LEN) {
+                write!(response, "More samples with capture vcd {}", next).ok();
+            }
+        }
+        _ => { write!(response, "usage: capture [start rate [r|a|b|c|d|alias|power][+|-|*]|stop|vcd [index]]").ok(); }
+    }
+}
+
+// index of a line in the pins log, power first then PINS order
+fn line_index(line: Line) -> usize {
+    match line {
+        Line::Power => 0,
+        Line::Pin(pin) => 1 + sequence::pin_index(pin),
+    }
+}
+
+// a sampling rate in Hz, with an optional k suffix for kHz
+fn parse_rate(rate: &str) -> Option<u32> {
+    match rate.strip_suffix('k') {
+        Some(khz) => khz.parse::<u32>().ok()?.checked_mul(1000),
+        None => rate.parse().ok(),
+    }
+}
+
+// a pin, an alias or power, followed by + for a rising edge, - for a falling
+// edge or * for any edge
+fn parse_trigger(trigger: &str, aliases: &Aliases) -> Option<Trigger> {
+    let (name, edge) = match trigger.as_bytes().last()? {
+        b'+' => (&trigger[..trigger.len() - 1], Some(true)),
+        b'-' => (&trigger[..trigger.len() - 1], Some(false)),
+        b'*' => (&trigger[..trigger.len() - 1], None),
+        _    => return None,
+    };
+    let line = match name {
+        "power" => Line::Power,
+        _       => Line::Pin(pin_from_name(name, aliases)?),
+    };
+    Some(Trigger::Edge(line, edge))
+}
+
+// a time in microseconds as milliseconds, with the 100us resolution of the timer
+fn write_ms<W>(w: &mut W, us: u64)
+where
+    W: Write
+ {
+    write!(w, "{:>8}.{}ms", us / 1000, us % 1000 / 100).ok();
+}
+
+fn handle_pwm_cmd<B, C>(response:&mut B, args: &str, ctl_pins:&mut C, config: &ConfigArea)
+where
+    B: Write,
+    C: CTLPinsTrait
+ {
+    let aliases = config.get().aliases;
+    let mut split_args = args.split_whitespace();
+    let (pin, hz, duty) = match (split_args.next(), split_args.next(), split_args.next(), split_args.next()) {
+        (Some(pin), Some(hz), Some(duty), None) => (pin_from_name(pin, &aliases), parse_rate(hz), duty.parse().ok()),
+        _ => (None, None, None),
+    };
+    let (pin, hz, duty) = match (pin, hz, duty) {
+        (Some(pin), Some(hz), Some(duty)) => (pin, hz, duty),
+        _ => {
+            write!(response, "usage: pwm r|a|b|c|d|alias frequency duty, i.e. pwm a 1k 50").ok();
             return;
         }
+    };
 
-        if val != 'l' && val != 'h' && val != 'z' {
-            write_set_usage(response);
+    match ctl_pins.set_pwm(pin, hz, duty) {
+        Ok(outcome) => {
+            write!(response, "PWM on ").ok();
+            write_pin_name(response, pin, &aliases);
+            write!(response, " at {}Hz {}%", hz, duty).ok();
+            if outcome == SetOutcome::Deferred {
+                write!(response, ", deferred until the DUT is powered on").ok();
+            }
+        }
+        Err(e) => { write!(response, "Error: {}", e).ok(); }
+    }
+}
+
+fn handle_i2c_cmd<B, C>(response:&mut B, args: &str, ctl_pins:&mut C, config: &ConfigArea)
+where
+    B: Write,
+    C: CTLPinsTrait
+ {
+    let (scl, sda) = match config.get().i2c_pins() {
+        Some(pins) => pins,
+        None => {
+            write!(response, "Error: {}, see set-config i2c", I2cError::NoPins).ok();
             return;
         }
+    };
+    let mut split_args = args.split_whitespace();
+    let cmd = split_args.next();
+    let addr = split_args.next().map(parse_byte);
+    // the remaining arguments are bytes, or the register and length of a read
+    let mut bytes = heapless::Vec::<u8, MAX_I2C_LEN>::new();
+    let mut valid = true;
+    for arg in split_args {
+        match parse_byte(arg) {
+            Some(b) if bytes.push(b).is_ok() => {}
+            _ => valid = false,
+        }
+    }
+    match (cmd, addr) {
+        (Some("scan"), None) => {
+            let mut found = 0;
+            let result = ctl_pins.i2c_scan(scl, sda, &mut |addr| {
+                write!(response, "{}0x{:02x}", if found == 0 { "Found " } else { ", " }, addr).ok();
+                found += 1;
+            });
+            match result {
+                Ok(()) if found == 0 => { write!(response, "No devices found").ok(); }
+                Ok(()) => {}
+                Err(e) => { write!(response, "{}Error: {}", if found > 0 { CR } else { "" }, e).ok(); }
+            }
+        }
+        (Some("read"), Some(Some(addr))) if valid && (bytes.len() == 1 || bytes.len() == 2) => {
+            let (reg, len) = match bytes.len() {
+                1 => (None, bytes[0] as usize),
+                _ => (Some(bytes[0]), bytes[1] as usize),
+            };
+            if len == 0 || len > MAX_I2C_LEN {
+                write!(response, "The length must be between 1 and {}", MAX_I2C_LEN).ok();
+                return;
+            }
+            let mut buf = [0u8; MAX_I2C_LEN];
+            let write = match &reg {
+                Some(reg) => core::slice::from_ref(reg),
+                None => &[],
+            };
+            match ctl_pins.i2c_transfer(scl, sda, addr, write, &mut buf[..len]) {
+                Ok(()) => {
+                    for (i, b) in buf[..len].iter().enumerate() {
+                        write!(response, "{}{:02x}", if i == 0 { "" } else { " " }, b).ok();
+                    }
+                }
+                Err(e) => { write!(response, "Error: {}", e).ok(); }
+            }
+        }
+        (Some("write"), Some(Some(addr))) if valid && !bytes.is_empty() => {
+            match ctl_pins.i2c_transfer(scl, sda, addr, &bytes, &mut []) {
+                Ok(()) => { write!(response, "Wrote {} bytes to 0x{:02x}", bytes.len(), addr).ok(); }
+                Err(e) => { write!(response, "Error: {}", e).ok(); }
+            }
+        }
+        _ => {
+            write!(response, "usage: i2c scan|read addr [reg] len|write addr byte..., addresses up to 0x{:02x}",
+                   MAX_ADDRESS).ok();
+        }
+    }
+}
 
-        let ctl_str = match ctl {
-            'r' => "/RESET",
-            'a' => "CTL_A",
-            'b' => "CTL_B",
-            'c' => "CTL_C",
-            'd' => "CTL_D",
-            _ => "",
-        };
+// a byte in decimal, or in hexadecimal with the 0x prefix
+fn parse_byte(byte: &str) -> Option<u8> {
+    match byte.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => byte.parse().ok(),
+    }
+}
 
-        let val_str = match val {
-            'l' => "LOW",
-            'h' => "HIGH",
-            'z' => "HIGH IMPEDANCE",
-            _ => "",
+fn handle_set_cmd<B, C>(response:&mut B, args: &str, ctl_pins:&mut C, config: &ConfigArea)
+where
+    B: Write,
+    C: CTLPinsTrait
+ {
+    let aliases = config.get().aliases;
+    let mut split_args = args.split_whitespace();
+    // pin and state pairs, several pins are set together
+    let mut states: PinStates = [None; 5];
+    let mut count = 0;
+    loop {
+        let (pin, state) = match (split_args.next(), split_args.next()) {
+            (None, _) if count > 0 => break,
+            (Some(pin), Some(state)) => (pin_from_name(pin, &aliases), pin_state_from_name(state)),
+            _ => (None, None),
         };
+        match (pin, state) {
+            (Some(pin), Some(state)) if states[sequence::pin_index(pin)].is_none() => {
+                states[sequence::pin_index(pin)] = Some(state);
+                count += 1;
+            }
+            _ => {
+                write_set_usage(response);
+                return;
+            }
+        }
+    }
 
-        let ps = match val {
-            'l' => PinState::Low,
-            'h' => PinState::High,
-            'z' => PinState::Floating,
-            _ => PinState::Floating,
-        };
+    if count > 1 {
+        let outcomes = ctl_pins.set_pins(&states);
+        write!(response, "Set").ok();
+        let mut sep = " ";
+        for ((pin, state), outcome) in PINS.iter().zip(states).zip(outcomes) {
+            if let (Some(state), Some(outcome)) = (state, outcome) {
+                write!(response, "{}", sep).ok();
+                write_pin_name(response, *pin, &aliases);
+                write!(response, " to {}", pin_state_name(state)).ok();
+                if outcome == SetOutcome::Deferred {
+                    write!(response, " (deferred until power on)").ok();
+                }
+                sep = ", ";
+            }
+        }
+        write!(response, " together").ok();
+        return;
+    }
 
-        match ctl {
-            'r' => ctl_pins.set_reset(ps),
-            'a' => ctl_pins.set_ctl_a(ps),
-            'b' => ctl_pins.set_ctl_b(ps),
-            'c' => ctl_pins.set_ctl_c(ps),
-            'd' => ctl_pins.set_ctl_d(ps),
-            _ => {},
-        };
+    let (pin, state) = PINS.iter().zip(states).find_map(|(pin, state)| state.map(|s| (*pin, s))).unwrap();
+    let outcome = match pin {
+        Pin::Reset => ctl_pins.set_reset(state),
+        Pin::A     => ctl_pins.set_ctl_a(state),
+        Pin::B     => ctl_pins.set_ctl_b(state),
+        Pin::C     => ctl_pins.set_ctl_c(state),
+        Pin::D     => ctl_pins.set_ctl_d(state),
+    };
 
-        write!(response, "Set {} to {}", ctl_str, val_str).ok();
-    } else {
-        write_set_usage(response)
+    write!(response, "Set ").ok();
+    write_pin_name(response, pin, &aliases);
+    write!(response, " to {}", pin_state_name(state)).ok();
+    if outcome == SetOutcome::Deferred {
+        // the pin is left as it was, driving it high would back-power the DUT
+        write!(response, ", deferred until the DUT is powered on").ok();
     }
 }
 
-fn handle_set_config_cmd<B>(response:&mut B, args: &str, config: &mut ConfigArea)
+fn handle_set_config_cmd<B, C>(response:&mut B, args: &str, ctl_pins: &mut C, config: &mut ConfigArea)
 where
-    B: Write
+    B: Write,
+    C: CTLPinsTrait
  {
-    let mut split_args = args.split_ascii_whitespace();
+    // the value is the rest of the line, so it can contain spaces
+    let mut split_args = args.splitn(2, ' ');
     let key = split_args.next();
-    let mut val = split_args.next();
+    let mut val = split_args.next().map(|v| v.trim());
     let mut usage = false;
 
     // empty argument = clear
@@ -316,19 +856,58 @@ where
             write!(response, "Set usb_console to {}", v).ok();
             config.write_config(&cfg).ok();
 
-        } else if k == "power_on" {
-            let cfg = cfg.set_power_on(v.as_bytes());
-            write!(response, "Set power_on to {}", v).ok();
-            config.write_config(&cfg).ok();
-
-        } else if k == "power_off" {
-            let cfg = cfg.set_power_off(v.as_bytes());
-            write!(response, "Set power_off to {}", v).ok();
-            config.write_config(&cfg).ok();
-        } else if k == "power_rescue" {
-            let cfg = cfg.set_power_rescue(v.as_bytes());
-            write!(response, "Set power_rescue to {}", v).ok();
+        } else if k == "power_on" || k == "power_off" || k == "power_rescue" {
+            if let Err(e) = sequence::parse(v.as_bytes(), &cfg.aliases) {
+                write!(response, "Invalid sequence for {}, {}", k, e).ok();
+                return;
+            }
+            let seqs = match k {
+                "power_on" => config.sequences().set_power_on(v.as_bytes()),
+                "power_off" => config.sequences().set_power_off(v.as_bytes()),
+                _ => config.sequences().set_power_rescue(v.as_bytes()),
+            };
+            match seqs {
+                Ok(seqs) => {
+                    write!(response, "Set {} to {}", k, v).ok();
+                    config.write_sequences(&seqs).ok();
+                }
+                Err(e) => { write!(response, "Error: {} {}", k, e).ok(); }
+            }
+        } else if let Some(pin) = alias_key_pin(k) {
+            match cfg.set_alias(pin, v.as_bytes()) {
+                Ok(cfg) => {
+                    write!(response, "Set {} to {}", k, v).ok();
+                    config.write_config(&cfg).ok();
+                    ctl_pins.set_aliases(cfg.aliases);
+                }
+                Err(e) => { write!(response, "Error: {} {}", k, e).ok(); }
+            }
+        } else if k == "i2c" {
+            // empty argument = clear
+            let mut pins = v.split_whitespace().map(|name| pin_from_name(name, &cfg.aliases));
+            let pins = match (pins.next(), pins.next(), pins.next()) {
+                (None, _, _) => None,
+                (Some(Some(scl)), Some(Some(sda)), None) if scl != sda => Some((scl, sda)),
+                _ => {
+                    write!(response, "usage: set-config i2c r|a|b|c|d|alias r|a|b|c|d|alias, SCL and SDA").ok();
+                    return;
+                }
+            };
+            let cfg = cfg.set_i2c_pins(pins);
             config.write_config(&cfg).ok();
+            write!(response, "Set i2c to ").ok();
+            write_i2c_pins(response, &cfg);
+        } else if let Some(pin) = boot_key_pin(k) {
+            // empty argument = floating, the default
+            let state = if v.is_empty() { Some(PinState::Floating) } else { pin_state_from_name(v) };
+            match state {
+                Some(state) => {
+                    let cfg = cfg.set_boot_state(pin, state);
+                    config.write_config(&cfg).ok();
+                    write!(response, "Set {} to {}, applied at boot", k, pin_state_name(state)).ok();
+                }
+                None => { write!(response, "Unknown state {}, expected l, h, z, o, u or d", v).ok(); }
+            }
         } else {
             usage = true;
         }
@@ -337,7 +916,118 @@ where
     }
 
     if usage {
-        write!(response, "usage: set-config name|tags|storage|usb_storage value").ok();
+        write!(response, "usage: set-config name|tags|json|usb_console|power_on|power_off|power_rescue|alias_r|alias_a|alias_b|alias_c|alias_d|boot_r|boot_a|boot_b|boot_c|boot_d|i2c value").ok();
+    }
+}
+
+// the config keys of the pin aliases
+fn alias_key_pin(key: &str) -> Option<Pin> {
+    match key {
+        "alias_r" => Some(Pin::Reset),
+        "alias_a" => Some(Pin::A),
+        "alias_b" => Some(Pin::B),
+        "alias_c" => Some(Pin::C),
+        "alias_d" => Some(Pin::D),
+        _         => None,
+    }
+}
+
+// the config keys of the pin states at boot
+fn boot_key_pin(key: &str) -> Option<Pin> {
+    match key {
+        "boot_r" => Some(Pin::Reset),
+        "boot_a" => Some(Pin::A),
+        "boot_b" => Some(Pin::B),
+        "boot_c" => Some(Pin::C),
+        "boot_d" => Some(Pin::D),
+        _        => None,
+    }
+}
+
+fn handle_run_cmd<B, C>(response:&mut B, args: &str, ctl_pins: &mut C, config: &ConfigArea)
+where
+    B: Write,
+    C: CTLPinsTrait
+ {
+    if args.len() == 0 {
+        write!(response, "usage: run name").ok();
+        return;
+    }
+    match config.sequences().get_sequence(args.as_bytes()) {
+        Some(entry) => {
+            match ctl_pins.run_sequence(entry.sequence()) {
+                Ok(()) => { write!(response, "Running sequence {}", args).ok(); }
+                Err(e) => { write!(response, "Error: {}", e).ok(); }
+            }
+        }
+        None => { write!(response, "Unknown sequence {}, see seq list", args).ok(); }
+    }
+}
+
+fn handle_seq_cmd<B, C>(response:&mut B, args: &str, ctl_pins: &mut C, config: &mut ConfigArea)
+where
+    B: Write,
+    C: CTLPinsTrait
+ {
+    let mut split_args = args.splitn(3, ' ');
+    let seqs = config.sequences();
+
+    match (split_args.next(), split_args.next(), split_args.next()) {
+        (Some("list"), None, None) => {
+            write!(response, "Stored sequences:").ok();
+            for entry in seqs.sequences.iter().filter(|s| !s.is_empty()) {
+                write!(response, "{}", CR).ok();
+                write_u8(response, entry.name());
+            }
+        }
+        (Some("show"), Some(name), None) => {
+            match seqs.get_sequence(name.as_bytes()) {
+                Some(entry) => write_u8(response, entry.sequence()),
+                None => { write!(response, "Unknown sequence {}", name).ok(); }
+            }
+        }
+        (Some("set"), Some(name), Some(seq)) => {
+            let seq = seq.trim();
+            if let Err(e) = sequence::parse(seq.as_bytes(), &config.get().aliases) {
+                write!(response, "Invalid sequence for {}, {}", name, e).ok();
+                return;
+            }
+            match seqs.set_sequence(name.as_bytes(), seq.as_bytes()) {
+                Ok(seqs) => {
+                    config.write_sequences(&seqs).ok();
+                    write!(response, "Set sequence {} to {}", name, seq).ok();
+                }
+                Err(e) => { write!(response, "Error: {}", e).ok(); }
+            }
+        }
+        (Some("del"), Some(name), None) => {
+            match seqs.delete_sequence(name.as_bytes()) {
+                Ok(seqs) => {
+                    config.write_sequences(&seqs).ok();
+                    write!(response, "Deleted sequence {}", name).ok();
+                }
+                Err(e) => { write!(response, "Error: sequence {} {}", name, e).ok(); }
+            }
+        }
+        (Some(cmd @ ("trace" | "dry-run")), Some(_), _) => {
+            // the argument is a stored sequence name, or a sequence which may contain spaces
+            let arg = args[cmd.len()..].trim();
+            let seq = match seqs.get_sequence(arg.as_bytes()) {
+                Some(entry) => entry.sequence(),
+                None => arg.as_bytes(),
+            };
+            let dry_run = cmd == "dry-run";
+            match ctl_pins.trace_sequence(seq, dry_run) {
+                Ok(()) => {
+                    write!(response, "{} ", if dry_run { "Dry-running" } else { "Tracing" }).ok();
+                    write_u8(response, seq);
+                }
+                Err(e) => { write!(response, "Error: {}", e).ok(); }
+            }
+        }
+        _ => {
+            write!(response, "usage: seq list|show name|set name sequence|del name|trace name|dry-run name").ok();
+        }
     }
 }
 
@@ -346,6 +1036,7 @@ where
     B: Write
  {
     let cfg = config.get();
+    let seqs = config.sequences();
 
     if args == "name" {
         write_u8(response, &cfg.name);
@@ -356,11 +1047,17 @@ where
     } else if args == "usb_console" {
         write_u8(response, &cfg.usb_console);
     } else if args == "power_on" {
-        write_u8(response, &cfg.power_on);
+        write_u8(response, &seqs.power_on);
     } else if args == "power_off" {
-        write_u8(response, &cfg.power_off);
+        write_u8(response, &seqs.power_off);
     } else if args == "power_rescue" {
-        write_u8(response, &cfg.power_rescue);
+        write_u8(response, &seqs.power_rescue);
+    } else if let Some(pin) = alias_key_pin(args) {
+        write_u8(response, cfg.alias(pin));
+    } else if let Some(pin) = boot_key_pin(args) {
+        write!(response, "{}", pin_state_name(cfg.boot_state(pin))).ok();
+    } else if args == "i2c" {
+        write_i2c_pins(response, cfg);
     } else if args == "" {
         write!(response, "name: ").ok();
         write_u8(response, &cfg.name);
@@ -371,13 +1068,37 @@ where
         write!(response, "\r\nusb_console: ").ok();
         write_u8(response, &cfg.usb_console);
         write!(response, "\r\npower_on: ").ok();
-        write_u8(response, &cfg.power_on);
+        write_u8(response, &seqs.power_on);
         write!(response, "\r\npower_off: ").ok();
-        write_u8(response, &cfg.power_off);
+        write_u8(response, &seqs.power_off);
         write!(response, "\r\npower_rescue: ").ok();
-        write_u8(response, &cfg.power_rescue);
+        write_u8(response, &seqs.power_rescue);
+        for (key, pin) in ["alias_r", "alias_a", "alias_b", "alias_c", "alias_d"].iter().zip(PINS) {
+            write!(response, "\r\n{}: ", key).ok();
+            write_u8(response, cfg.alias(pin));
+        }
+        for (key, pin) in ["boot_r", "boot_a", "boot_b", "boot_c", "boot_d"].iter().zip(PINS) {
+            write!(response, "\r\n{}: {}", key, pin_state_name(cfg.boot_state(pin))).ok();
+        }
+        write!(response, "\r\ni2c: ").ok();
+        write_i2c_pins(response, cfg);
     } else {
-        write!(response, "usage: get-config [name|tags|json|usb_console|power_on|power_off|power_rescue]").ok();
+        write!(response, "usage: get-config [name|tags|json|usb_console|power_on|power_off|power_rescue|alias_r|alias_a|alias_b|alias_c|alias_d|boot_r|boot_a|boot_b|boot_c|boot_d|i2c]").ok();
+    }
+}
+
+fn write_i2c_pins<B>(response:&mut B, cfg: &ConfigBlock)
+where
+    B: Write
+ {
+    match cfg.i2c_pins() {
+        Some((scl, sda)) => {
+            write!(response, "SCL ").ok();
+            write_pin_name(response, scl, &cfg.aliases);
+            write!(response, ", SDA ").ok();
+            write_pin_name(response, sda, &cfg.aliases);
+        }
+        None => { write!(response, "not set").ok(); }
     }
 }
 
@@ -397,15 +1118,26 @@ fn write_set_usage<B>(response:&mut B)
 where
     B: Write
  {
-    write!(response, "usage: set r|a|b|c|d l|h|z").ok();
+    write!(response, "usage: set r|a|b|c|d|alias l|h|z|o|u|d [r|a|b|c|d|alias l|h|z|o|u|d]...").ok();
 }
 
-fn handle_status_cmd<B>(response:&mut B, args: &str, shell_status: &mut ShellStatus)
+fn handle_status_cmd<B, C>(response:&mut B, args: &str, shell_status: &mut ShellStatus, ctl_pins: &mut C,
+                          config: &ConfigArea)
 where
-    B: Write
+    B: Write,
+    C: CTLPinsTrait
  {
     if args =="" {
-        write!(response, "Monitor: {}, Meter: {}", shell_status.monitor_enabled, shell_status.meter_enabled).ok();
+        let sequence = match ctl_pins.sequence_state() {
+            SequenceState::Idle    => "idle",
+            SequenceState::Running => "running",
+            SequenceState::Aborted => "aborted",
+            SequenceState::TimedOut => "timed out",
+        };
+        write!(response, "Monitor: {}, Meter: {}, Sequence: {}",
+               shell_status.monitor_enabled, shell_status.meter_enabled, sequence).ok();
+        write!(response, "{}Device: ", CR).ok();
+        write_power_state(response, ctl_pins, &config.get().aliases);
     } else {
         write!(response, "usage: status").ok();
     }